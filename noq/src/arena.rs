@@ -0,0 +1,429 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    index::{IndexStats, RuleIndex},
+    is_var_name,
+    number::{self, Number},
+    theory::{self, Theories},
+    Expr, NoqError, Path, Rule, Stop,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NameId(u32);
+
+/// Two ids from the same arena are equal if and only if the expressions they
+/// stand for are structurally equal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ExprId(u32);
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Node {
+    Sym(NameId),
+    Var(NameId),
+    Num(Number),
+    Fun(NameId, Vec<ExprId>),
+}
+
+pub type IdBindings = HashMap<NameId, ExprId>;
+
+/// A rule with its head and body interned, keeping the rule itself around for
+/// what the arena cannot do.
+#[derive(Debug, Clone, Copy)]
+pub struct IdRule<'a> {
+    pub head: ExprId,
+    pub body: ExprId,
+    pub rule: &'a Rule,
+}
+
+/// A rewrite step: the index of the rule that fired, or None for built-in
+/// arithmetic, the path to the redex and the resulting expression.
+pub type IdStep = (Option<usize>, Path, ExprId);
+
+/// Hash-consed expressions: every distinct subexpression is stored once, so
+/// equality is a comparison of ids and rewriting only allocates the nodes on
+/// the path to the redex.
+#[derive(Debug, Default)]
+pub struct Arena {
+    names: Vec<String>,
+    name_ids: HashMap<String, NameId>,
+    nodes: Vec<Node>,
+    // Whether the node has no variables, in which case substituting into it
+    // is a no-op.
+    ground: Vec<bool>,
+    node_ids: HashMap<Node, ExprId>,
+}
+
+impl Arena {
+    pub fn name_id(&mut self, name: &str) -> NameId {
+        if let Some(&id) = self.name_ids.get(name) {
+            return id;
+        }
+        let id = NameId(self.names.len() as u32);
+        self.names.push(name.to_string());
+        self.name_ids.insert(name.to_string(), id);
+        id
+    }
+
+    pub fn name(&self, id: NameId) -> &str {
+        &self.names[id.0 as usize]
+    }
+
+    pub fn node(&self, id: ExprId) -> &Node {
+        &self.nodes[id.0 as usize]
+    }
+
+    fn is_ground(&self, id: ExprId) -> bool {
+        self.ground[id.0 as usize]
+    }
+
+    pub fn insert(&mut self, node: Node) -> ExprId {
+        if let Some(&id) = self.node_ids.get(&node) {
+            return id;
+        }
+        let ground = match &node {
+            Node::Sym(_) | Node::Num(_) => true,
+            Node::Var(_) => false,
+            // A functor named like a variable can be substituted too.
+            Node::Fun(name, args) => {
+                !is_var_name(self.name(*name)) && args.iter().all(|&arg| self.is_ground(arg))
+            }
+        };
+        let id = ExprId(self.nodes.len() as u32);
+        self.nodes.push(node.clone());
+        self.ground.push(ground);
+        self.node_ids.insert(node, id);
+        id
+    }
+
+    pub fn intern(&mut self, expr: &Expr) -> ExprId {
+        let node = match expr {
+            Expr::Sym(name) => Node::Sym(self.name_id(name)),
+            Expr::Var(name) => Node::Var(self.name_id(name)),
+            Expr::Num(number) => Node::Num(*number),
+            Expr::Fun(name, args) => {
+                let name = self.name_id(name);
+                let args = args.iter().map(|arg| self.intern(arg)).collect();
+                Node::Fun(name, args)
+            }
+        };
+        self.insert(node)
+    }
+
+    pub fn intern_rule<'a>(&mut self, rule: &'a Rule) -> IdRule<'a> {
+        IdRule {
+            head: self.intern(&rule.head),
+            body: self.intern(&rule.body),
+            rule,
+        }
+    }
+
+    pub fn to_expr(&self, id: ExprId) -> Expr {
+        match self.node(id) {
+            Node::Sym(name) => Expr::Sym(self.name(*name).to_string()),
+            Node::Var(name) => Expr::Var(self.name(*name).to_string()),
+            Node::Num(number) => Expr::Num(*number),
+            Node::Fun(name, args) => Expr::Fun(
+                self.name(*name).to_string(),
+                args.iter().map(|&arg| self.to_expr(arg)).collect(),
+            ),
+        }
+    }
+
+    /// Same as `crate::theory::pattern_match` without theories, but checking a
+    /// bound variable against its value is a comparison of ids instead of a
+    /// walk over both trees. Takes `&mut self` because functor variables are
+    /// bound to symbols that might not be in the arena yet.
+    pub fn pattern_match(&mut self, pattern: ExprId, value: ExprId) -> Option<IdBindings> {
+        let mut bindings = IdBindings::new();
+        if self.pattern_match_impl(pattern, value, &mut bindings) {
+            Some(bindings)
+        } else {
+            None
+        }
+    }
+
+    fn pattern_match_impl(
+        &mut self,
+        pattern: ExprId,
+        value: ExprId,
+        bindings: &mut IdBindings,
+    ) -> bool {
+        if pattern == value && self.is_ground(pattern) {
+            return true;
+        }
+        match (self.node(pattern), self.node(value)) {
+            (Node::Var(name), _) => match bindings.get(name) {
+                Some(&bound_value) => bound_value == value,
+                None => {
+                    bindings.insert(*name, value);
+                    true
+                }
+            },
+            (Node::Fun(name1, args1), Node::Fun(name2, args2)) => {
+                if args1.len() != args2.len() {
+                    return false;
+                }
+                let (name1, name2, arity) = (*name1, *name2, args1.len());
+                if is_var_name(self.name(name1)) {
+                    let functor = self.insert(Node::Sym(name2));
+                    match bindings.get(&name1) {
+                        Some(&bound_value) if bound_value != functor => return false,
+                        Some(_) => {}
+                        None => {
+                            bindings.insert(name1, functor);
+                        }
+                    }
+                } else if name1 != name2 {
+                    return false;
+                }
+                (0..arity).all(|i| {
+                    let (Node::Fun(_, args1), Node::Fun(_, args2)) =
+                        (self.node(pattern), self.node(value))
+                    else {
+                        unreachable!()
+                    };
+                    let (arg1, arg2) = (args1[i], args2[i]);
+                    self.pattern_match_impl(arg1, arg2, bindings)
+                })
+            }
+            // Symbols and numbers are interned, so equal ones have equal ids.
+            (Node::Sym(_) | Node::Num(_), _) => pattern == value,
+            (Node::Fun(_, _), _) => false,
+        }
+    }
+
+    /// Same as `crate::substitute_bindings`. Subexpressions without variables
+    /// are shared with `expr` rather than copied.
+    pub fn substitute_bindings(
+        &mut self,
+        bindings: &IdBindings,
+        expr: ExprId,
+    ) -> Result<ExprId, NoqError> {
+        if self.is_ground(expr) {
+            return Ok(expr);
+        }
+        match self.node(expr).clone() {
+            Node::Sym(_) | Node::Num(_) => Ok(expr),
+            Node::Var(name) => Ok(bindings.get(&name).copied().unwrap_or(expr)),
+            Node::Fun(name, args) => {
+                let new_name = match bindings.get(&name).map(|&id| (id, self.node(id))) {
+                    Some((_, Node::Sym(new_name) | Node::Var(new_name))) => *new_name,
+                    None => name,
+                    Some((value, _)) => {
+                        return Err(NoqError::FunctorNotSymbol {
+                            loc: None,
+                            name: self.name(name).to_string(),
+                            value: self.to_expr(value),
+                        })
+                    }
+                };
+                let mut new_args = Vec::new();
+                for &arg in &args {
+                    new_args.push(self.substitute_bindings(bindings, arg)?);
+                }
+                Ok(self.insert(Node::Fun(new_name, new_args)))
+            }
+        }
+    }
+
+    // Folds an arithmetic operator applied to two numbers.
+    fn evaluate(&mut self, expr: ExprId) -> Option<ExprId> {
+        let Node::Fun(name, args) = self.node(expr) else {
+            return None;
+        };
+        let [a, b] = args[..] else {
+            return None;
+        };
+        let (Node::Num(a), Node::Num(b)) = (self.node(a), self.node(b)) else {
+            return None;
+        };
+        let number = number::evaluate(self.name(*name), *a, *b)?;
+        Some(self.insert(Node::Num(number)))
+    }
+
+    // Rewrites the leftmost-outermost redex, with the first rule in
+    // definition order that matches there, or else built-in arithmetic. The
+    // index narrows down the rules
+    // tried at each subexpression, and subexpressions that turned out to have
+    // no redex are remembered in `irreducible` and never visited again.
+    fn rewrite_step(
+        &mut self,
+        rules: &[IdRule],
+        index: &RuleIndex,
+        theories: &Theories,
+        irreducible: &mut HashSet<ExprId>,
+        expr: ExprId,
+        stats: &mut IndexStats,
+    ) -> Result<Option<IdStep>, NoqError> {
+        if irreducible.contains(&expr) {
+            return Ok(None);
+        }
+        for rule in index.candidates(self, expr, stats) {
+            let IdRule {
+                head,
+                body,
+                rule: tree_rule,
+            } = rules[rule];
+            let new_expr = if index.is_modulo(rule) || !tree_rule.guards.is_empty() {
+                // Matching modulo theories and checking guards work on trees,
+                // the former can bind variables to expressions that are not
+                // subexpressions.
+                let value = self.to_expr(expr);
+                let Some(new_expr) = theory::rewrite(tree_rule, &value, theories)? else {
+                    continue;
+                };
+                self.intern(&new_expr)
+            } else {
+                let Some(bindings) = self.pattern_match(head, expr) else {
+                    continue;
+                };
+                self.substitute_bindings(&bindings, body)?
+            };
+            stats.matches += 1;
+            return Ok(Some((Some(rule), Path::new(), new_expr)));
+        }
+        if let Some(new_expr) = self.evaluate(expr) {
+            return Ok(Some((None, Path::new(), new_expr)));
+        }
+        let arity = match self.node(expr) {
+            Node::Fun(_, args) => args.len(),
+            Node::Sym(_) | Node::Var(_) | Node::Num(_) => 0,
+        };
+        for i in 0..arity {
+            let Node::Fun(_, args) = self.node(expr) else {
+                unreachable!()
+            };
+            if let Some((rule, mut path, new_arg)) =
+                self.rewrite_step(rules, index, theories, irreducible, args[i], stats)?
+            {
+                let Node::Fun(name, mut args) = self.node(expr).clone() else {
+                    unreachable!()
+                };
+                args[i] = new_arg;
+                // The path is built from the redex up and reversed by
+                // `normalize`.
+                path.push(i);
+                return Ok(Some((rule, path, self.insert(Node::Fun(name, args)))));
+            }
+        }
+        irreducible.insert(expr);
+        Ok(None)
+    }
+
+    /// Same as `crate::normalize`, returning every step.
+    pub fn normalize(
+        &mut self,
+        rules: &[IdRule],
+        theories: &Theories,
+        expr: ExprId,
+        max_steps: usize,
+        stats: &mut IndexStats,
+    ) -> Result<(Vec<IdStep>, Stop), NoqError> {
+        let heads: Vec<ExprId> = rules.iter().map(|rule| rule.head).collect();
+        let index = RuleIndex::new(self, &heads, theories);
+        let mut irreducible = HashSet::new();
+        let mut seen = HashSet::from([expr]);
+        let mut steps: Vec<IdStep> = Vec::new();
+        loop {
+            let current = steps.last().map_or(expr, |&(_, _, id)| id);
+            let Some((rule, mut path, next)) =
+                self.rewrite_step(rules, &index, theories, &mut irreducible, current, stats)?
+            else {
+                return Ok((steps, Stop::Normal));
+            };
+            if steps.len() >= max_steps {
+                return Ok((steps, Stop::StepLimit));
+            }
+            if !seen.insert(next) {
+                return Ok((steps, Stop::Cycle));
+            }
+            path.reverse();
+            steps.push((rule, path, next));
+        }
+    }
+}
+
+#[test]
+fn interned_expressions_are_shared() {
+    let mut arena = Arena::default();
+    let a = arena.intern(&"f(g(a), g(a))".parse().unwrap());
+    let b = arena.intern(&"f(g(a), g(a))".parse().unwrap());
+    assert_eq!(a, b);
+    let Node::Fun(_, args) = arena.node(a) else {
+        panic!("expected functor")
+    };
+    assert_eq!(args[0], args[1]);
+    assert_eq!(arena.to_expr(a).to_string(), "f(g(a), g(a))");
+}
+
+#[test]
+fn arena_matching_and_substitution() {
+    let mut arena = Arena::default();
+    let head = arena.intern(&"eq(X, X, zero)".parse().unwrap());
+    let body = arena.intern(&"pair(X, zero)".parse().unwrap());
+    let value = arena.intern(&"eq(f(a), f(a), zero)".parse().unwrap());
+    let bindings = arena.pattern_match(head, value).unwrap();
+    let result = arena.substitute_bindings(&bindings, body).unwrap();
+    assert_eq!(arena.to_expr(result).to_string(), "pair(f(a), zero)");
+
+    let value = arena.intern(&"eq(f(a), f(b), zero)".parse().unwrap());
+    assert!(arena.pattern_match(head, value).is_none());
+    let value = arena.intern(&"eq(a, a, one)".parse().unwrap());
+    assert!(arena.pattern_match(head, value).is_none());
+}
+
+// cargo test --release bench_arena -- --ignored --nocapture
+#[test]
+#[ignore]
+fn bench_arena() {
+    use crate::Strategy;
+    use std::time::Instant;
+
+    let rules: Vec<Rule> = [
+        "add(zero, X) = X",
+        "add(succ(X), Y) = succ(add(X, Y))",
+        "mul(zero, X) = zero",
+        "mul(succ(X), Y) = add(Y, mul(X, Y))",
+    ]
+    .iter()
+    .map(|rule| rule.parse().unwrap())
+    .collect();
+    let number = |n| (0..n).fold("zero".to_string(), |expr, _| format!("succ({})", expr));
+    let expr: Expr = format!("mul({}, {})", number(30), number(30))
+        .parse()
+        .unwrap();
+
+    // Rewriting owned trees, cloning them at every step. The rules are tried
+    // one after another rather than picking the leftmost-outermost redex, so
+    // the number of steps can differ but not the normal form.
+    let start = Instant::now();
+    let mut seen = HashSet::from([expr.clone()]);
+    let mut current = expr.clone();
+    let mut tree_steps = 0;
+    while let Some(next) = rules
+        .iter()
+        .find_map(|rule| rule.apply(&current, &Strategy::First).unwrap())
+    {
+        assert!(seen.insert(next.clone()));
+        current = next;
+        tree_steps += 1;
+    }
+    let tree_time = start.elapsed();
+    let tree_result = current;
+
+    let start = Instant::now();
+    let mut arena = Arena::default();
+    let arena_rules: Vec<IdRule> = rules.iter().map(|rule| arena.intern_rule(rule)).collect();
+    let id = arena.intern(&expr);
+    let mut stats = IndexStats::default();
+    let (steps, stop) = arena
+        .normalize(&arena_rules, &Theories::new(), id, usize::MAX, &mut stats)
+        .unwrap();
+    let arena_time = start.elapsed();
+
+    assert_eq!(stop, Stop::Normal);
+    assert_eq!(arena.to_expr(steps.last().unwrap().2), tree_result);
+    println!("tree:  {} steps in {:?}", tree_steps, tree_time);
+    println!("arena: {} steps in {:?}", steps.len(), arena_time);
+    println!("{:?}", stats);
+}
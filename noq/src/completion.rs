@@ -0,0 +1,173 @@
+use std::collections::VecDeque;
+
+use crate::{
+    critical::overlaps, normal_form, order::TermOrder, substitute_bindings, Bindings, Expr,
+    NoqError, Rule, Strategy,
+};
+
+/// Why completion gave up.
+#[derive(Debug)]
+pub enum CompletionError {
+    /// Neither side of the equation is greater than the other.
+    Unorientable {
+        /// The name the rule would have had.
+        name: String,
+        /// One side of the equation, normalized.
+        lhs: Expr,
+        /// The other side, normalized.
+        rhs: Expr,
+    },
+    /// Completion would need more than this many rules.
+    RuleLimit(usize),
+    /// Completion does not know about guards.
+    Conditional(String),
+    /// Normalizing with the rules found so far did not terminate, which can
+    /// only happen if `order` does not actually guarantee termination.
+    StepLimit(Expr),
+    /// Rewriting itself failed.
+    Noq(NoqError),
+}
+
+impl From<NoqError> for CompletionError {
+    fn from(error: NoqError) -> Self {
+        CompletionError::Noq(error)
+    }
+}
+
+/// Knuth-Bendix completion: turns `equations` into a confluent and
+/// terminating set of rules by orienting them with `order` and adding the
+/// critical pairs as new equations until every one of them is joinable.
+pub fn complete(
+    equations: &[(String, Rule)],
+    order: &dyn TermOrder,
+    max_rules: usize,
+    max_steps: usize,
+) -> Result<Vec<(String, Rule)>, CompletionError> {
+    if let Some((name, _)) = equations.iter().find(|(_, rule)| !rule.guards.is_empty()) {
+        return Err(CompletionError::Conditional(name.clone()));
+    }
+    let mut pending: VecDeque<(String, Expr, Expr)> = equations
+        .iter()
+        .map(|(name, rule)| (name.clone(), rule.head.clone(), rule.body.clone()))
+        .collect();
+    let mut rules: Vec<(String, Rule)> = Vec::new();
+    let mut generated = 0;
+
+    while let Some((name, lhs, rhs)) = pending.pop_front() {
+        let lhs = normal_form(&rules, &lhs, max_steps)?.map_err(CompletionError::StepLimit)?;
+        let rhs = normal_form(&rules, &rhs, max_steps)?.map_err(CompletionError::StepLimit)?;
+        if lhs == rhs {
+            continue;
+        }
+        let rule = if order.greater(&lhs, &rhs) {
+            rename_canonically(&lhs, &rhs)?
+        } else if order.greater(&rhs, &lhs) {
+            rename_canonically(&rhs, &lhs)?
+        } else {
+            return Err(CompletionError::Unorientable { name, lhs, rhs });
+        };
+
+        // Rules whose heads the new rule can rewrite are turned back into
+        // equations, the rest get their bodies simplified.
+        let mut kept = Vec::new();
+        for (old_name, old_rule) in rules {
+            if rule.apply(&old_rule.head, &Strategy::First)?.is_some() {
+                pending.push_back((old_name, old_rule.head, old_rule.body));
+            } else {
+                kept.push((old_name, old_rule));
+            }
+        }
+        rules = kept;
+        rules.push((name, rule));
+        for i in 0..rules.len() {
+            let body = normal_form(&rules, &rules[i].1.body, max_steps)?
+                .map_err(CompletionError::StepLimit)?;
+            rules[i].1.body = body;
+        }
+        if rules.len() > max_rules {
+            return Err(CompletionError::RuleLimit(max_rules));
+        }
+
+        let (new_name, new_rule) = rules.last().unwrap();
+        for (name, rule) in &rules {
+            let mut pairs = overlaps(new_name, new_rule, name, rule)?;
+            if name != new_name {
+                pairs.extend(overlaps(name, rule, new_name, new_rule)?);
+            }
+            for pair in pairs {
+                generated += 1;
+                pending.push_back((format!("cp{}", generated), pair.left, pair.right));
+            }
+        }
+    }
+    Ok(rules)
+}
+
+// Critical pairs come out with their variables renamed apart, so give the
+// variables of new rules readable names in order of appearance.
+fn rename_canonically(head: &Expr, body: &Expr) -> Result<Rule, NoqError> {
+    const NAMES: [&str; 6] = ["X", "Y", "Z", "U", "V", "W"];
+    let bindings: Bindings = head
+        .vars()
+        .into_iter()
+        .enumerate()
+        .map(|(i, var)| {
+            let name = match NAMES.get(i) {
+                Some(name) => name.to_string(),
+                None => format!("X{}", i),
+            };
+            (var, Expr::Var(name))
+        })
+        .collect();
+    Ok(Rule {
+        head: substitute_bindings(&bindings, head)?,
+        body: substitute_bindings(&bindings, body)?,
+        guards: Vec::new(),
+        reversible: false,
+    })
+}
+
+#[test]
+fn complete_group_axioms() {
+    use crate::{
+        critical::non_joinable_pairs,
+        order::{Lpo, Precedence},
+    };
+
+    let equations: Vec<(String, Rule)> = [
+        ("left_id", "e * X = X"),
+        ("left_inv", "i(X) * X = e"),
+        ("assoc", "(X * Y) * Z = X * (Y * Z)"),
+    ]
+    .iter()
+    .map(|(name, rule)| (name.to_string(), rule.parse().unwrap()))
+    .collect();
+    let precedence = Precedence(vec!["i".to_string(), "mul".to_string(), "e".to_string()]);
+    let rules = complete(&equations, &Lpo { precedence }, 100, 1000).unwrap();
+    assert!(non_joinable_pairs(&rules, 1000).unwrap().is_empty());
+
+    // The well known complete system for groups.
+    let mut rules: Vec<String> = rules.iter().map(|(_, rule)| rule.to_string()).collect();
+    rules.sort();
+    let mut expected = vec![
+        "e * X = X",
+        "X * e = X",
+        "i(X) * X = e",
+        "X * i(X) = e",
+        "X * Y * Z = X * (Y * Z)",
+        "i(X) * (X * Y) = Y",
+        "X * (i(X) * Y) = Y",
+        "i(e) = e",
+        "i(i(X)) = X",
+        "i(X * Y) = i(Y) * i(X)",
+    ];
+    expected.sort();
+    assert_eq!(rules, expected);
+}
+
+#[test]
+fn complete_fails_on_unorientable_equations() {
+    let equations = vec![("comm".to_string(), "X * Y = Y * X".parse().unwrap())];
+    let result = complete(&equations, &crate::order::Lpo::default(), 100, 1000);
+    assert!(matches!(result, Err(CompletionError::Unorientable { .. })));
+}
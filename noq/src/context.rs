@@ -0,0 +1,996 @@
+use std::{collections::HashMap, io::Write};
+
+use crate::{
+    completion::{complete, CompletionError},
+    critical::non_joinable_pairs,
+    egraph::{cost_function, EGraph, NodeCount, Saturation},
+    expect_op, expect_sym, expect_token,
+    index::IndexStats,
+    is_reserved_op, normalize,
+    order::{orientation, Kbo, Lpo, Orientation, Precedence, TermOrder},
+    parse_number, parse_strategy,
+    proof::{self, Format},
+    query::{solve, Clause},
+    render,
+    tactic::{self, Tactic},
+    theory::{Theories, Theory},
+    unify::unify,
+    Assoc, Expr, Lexer, Loc, NoqError, Operator, Operators, Path, Rule, Step, Stop, Token,
+    TokenKind, DEFAULT_MAX_STEPS,
+};
+
+fn parse_goals<Chars: Iterator<Item = char>>(
+    lexer: &mut Lexer<Chars>,
+    operators: &Operators,
+) -> Result<Vec<Expr>, NoqError> {
+    let mut goals = vec![Expr::parse(lexer, operators)?];
+    while matches!(lexer.peek(), Some(token) if token.kind == TokenKind::Comma) {
+        lexer.next();
+        goals.push(Expr::parse(lexer, operators)?);
+    }
+    Ok(goals)
+}
+
+const DEFAULT_MAX_RULES: usize = 100;
+const DEFAULT_MAX_ITERATIONS: usize = 30;
+const DEFAULT_MAX_DEPTH: usize = 100;
+const MAX_NODES: usize = 10_000;
+
+// order  := 'lpo' ['(' sym (',' sym)* ')']
+//         | 'kbo' ['(' weight (',' weight)* ')']
+// weight := sym | sym '(' number ')'
+//
+// The symbols are the precedence, from the greatest to the least. KBO weights
+// default to 1.
+fn parse_order<Chars: Iterator<Item = char>>(
+    lexer: &mut Lexer<Chars>,
+    operators: &Operators,
+) -> Result<Box<dyn TermOrder>, NoqError> {
+    let loc = lexer.loc();
+    let (name, args) = match Expr::parse(lexer, operators)? {
+        Expr::Sym(name) => (name, Vec::new()),
+        Expr::Fun(name, args) => (name, args),
+        expr => {
+            return Err(NoqError::Command {
+                loc,
+                message: format!("expected term order but got {}", operators.show(&expr)),
+            })
+        }
+    };
+    let mut precedence = Vec::new();
+    let mut weights = HashMap::new();
+    for arg in args {
+        let weight = match &arg {
+            Expr::Fun(_, weight) => match weight.as_slice() {
+                [Expr::Num(weight)] => weight
+                    .as_integer()
+                    .and_then(|weight| weight.try_into().ok()),
+                _ => None,
+            },
+            _ => None,
+        };
+        match (arg, weight) {
+            (Expr::Sym(name), _) => precedence.push(name),
+            (Expr::Fun(name, _), Some(weight)) => {
+                weights.insert(name.clone(), weight);
+                precedence.push(name);
+            }
+            (expr, _) => {
+                return Err(NoqError::Command {
+                    loc,
+                    message: format!(
+                        "expected functor name in precedence but got {}",
+                        operators.show(&expr)
+                    ),
+                })
+            }
+        }
+    }
+    let precedence = Precedence(precedence);
+    match name.as_str() {
+        "lpo" if weights.is_empty() => Ok(Box::new(Lpo { precedence })),
+        "kbo" => Kbo::new(precedence, weights)
+            .map(|kbo| Box::new(kbo) as Box<dyn TermOrder>)
+            .map_err(|message| NoqError::Command { loc, message }),
+        "lpo" => Err(NoqError::Command {
+            loc,
+            message: "lpo does not take weights".to_string(),
+        }),
+        _ => Err(NoqError::Command {
+            loc,
+            message: format!("unknown term order `{}`", name),
+        }),
+    }
+}
+
+// Fails if `order` cannot show that one of `rules` terminates, whatever the
+// orientation.
+fn check_order<'a>(
+    order: &dyn TermOrder,
+    rules: impl IntoIterator<Item = (&'a String, &'a Rule)>,
+    loc: Loc,
+) -> Result<(), NoqError> {
+    for (name, rule) in rules {
+        order.check(rule).map_err(|message| NoqError::Command {
+            loc,
+            message: format!("cannot order rule `{}`: {}", name, message),
+        })?;
+    }
+    Ok(())
+}
+
+struct Shape {
+    start: Expr,
+    steps: Vec<Step>,
+}
+
+impl Shape {
+    fn current(&self) -> &Expr {
+        self.steps.last().map_or(&self.start, |step| &step.expr)
+    }
+}
+
+/// The state of a REPL session or a script: the rules, theories, operators,
+/// clauses and tactics defined so far, and the shape being rewritten.
+#[derive(Default)]
+pub struct Context {
+    // Kept in definition order, which is the order normalization tries them.
+    rules: Vec<(String, Rule)>,
+    shape: Option<Shape>,
+    // The last shape that was done, kept around to be exported.
+    finished: Option<Shape>,
+    operators: Operators,
+    // Functors that `apply` and `normalize` match modulo a theory.
+    theories: Theories,
+    // When set, every rule has to be decreasing in it.
+    order: Option<Box<dyn TermOrder>>,
+    // How well the rule index did in all normalizations so far.
+    stats: IndexStats,
+    // Clauses that `query` proves goals with, in definition order.
+    program: Vec<Clause>,
+    // Tactics defined with `tactic`, by name.
+    tactics: Vec<(String, Tactic)>,
+}
+
+fn find_rule<'a>(rules: &'a [(String, Rule)], name: &str) -> Option<&'a Rule> {
+    rules
+        .iter()
+        .find(|(rule_name, _)| rule_name == name)
+        .map(|(_, rule)| rule)
+}
+
+impl Context {
+    fn show_clause(&self, clause: &Clause) -> String {
+        let mut text = self.operators.show(&clause.head).to_string();
+        for (index, goal) in clause.body.iter().enumerate() {
+            text += if index == 0 { " if " } else { ", " };
+            text += &self.operators.show(goal).to_string();
+        }
+        text
+    }
+
+    // A tactic written as an expression, naming rules and earlier tactics.
+    fn parse_tactic<Chars: Iterator<Item = char>>(
+        &self,
+        lexer: &mut Lexer<Chars>,
+    ) -> Result<Tactic, NoqError> {
+        let loc = lexer.loc();
+        let expr = Expr::parse(lexer, &self.operators)?;
+        let lookup = |name: &str| match self
+            .tactics
+            .iter()
+            .find(|(tactic_name, _)| tactic_name == name)
+        {
+            Some((_, tactic)) => Some(tactic.clone()),
+            None => find_rule(&self.rules, name).map(|rule| rule.tactic(name)),
+        };
+        tactic::from_expr(&expr, &lookup).map_err(|message| NoqError::Command { loc, message })
+    }
+
+    fn define_rule(&mut self, name: String, rule: Rule) {
+        match self
+            .rules
+            .iter_mut()
+            .find(|(rule_name, _)| *rule_name == name)
+        {
+            Some(entry) => entry.1 = rule,
+            None => self.rules.push((name, rule)),
+        }
+    }
+}
+
+// Writing the output can only fail if whatever it goes to is gone, which
+// stops the command like any other error.
+fn output_error(loc: Loc) -> impl FnOnce(std::io::Error) -> NoqError {
+    move |error| NoqError::Command {
+        loc,
+        message: format!("could not write output: {}", error),
+    }
+}
+
+impl Context {
+    /// Runs the next command from `lexer`, writing what it did to `out`.
+    ///
+    /// ```text
+    /// command := 'rule' sym rule
+    ///          | 'shape' expr
+    ///          | 'apply' sym [strategy]
+    ///          | 'matches' sym
+    ///          | 'normalize' [number]
+    ///          | 'saturate' [sym] [number]
+    ///          | 'infix' op sym number ('left' | 'right')
+    ///          | ('ac' | 'comm') sym
+    ///          | 'unify' expr '=' expr
+    ///          | 'confluence' [number]
+    ///          | 'complete' order [number]
+    ///          | 'order' order
+    ///          | 'stats'
+    ///          | 'undo'
+    ///          | 'history'
+    ///          | 'done'
+    ///          | 'export' ('text' | 'markdown' | 'json')
+    ///          | 'draw' ('tree' | 'dot' [sym])
+    ///          | 'fact' expr ['if' goals]
+    ///          | 'query' goals [number]
+    ///          | 'tactic' sym tactic
+    ///          | 'run' tactic
+    ///
+    /// goals    := expr (',' expr)*
+    /// ```
+    pub fn process_command<Chars: Iterator<Item = char>>(
+        &mut self,
+        lexer: &mut Lexer<Chars>,
+        out: &mut impl Write,
+    ) -> Result<(), NoqError> {
+        let loc = lexer.loc();
+        match expect_sym(lexer)?.as_str() {
+            "rule" => {
+                let name = expect_sym(lexer)?;
+                let rule = Rule::parse(lexer, &self.operators)?;
+                if let Some(order) = &self.order {
+                    check_order(order.as_ref(), [(&name, &rule)], loc)?;
+                    match orientation(order.as_ref(), &rule) {
+                        Orientation::Forward => {}
+                        Orientation::Backward => {
+                            return Err(NoqError::Command {
+                                loc,
+                                message: format!(
+                                    "rule `{}` makes expressions bigger, flip it around",
+                                    name
+                                ),
+                            })
+                        }
+                        Orientation::Unorientable => {
+                            return Err(NoqError::Command {
+                                loc,
+                                message: format!("rule `{}` cannot be oriented", name),
+                            })
+                        }
+                    }
+                }
+                writeln!(out, "rule {}: {}", name, self.operators.show_rule(&rule))
+                    .map_err(output_error(loc))?;
+                self.define_rule(name, rule);
+            }
+            "shape" => {
+                if let Some(shape) = &self.shape {
+                    return Err(NoqError::Command {
+                        loc,
+                        message: format!("already shaping {}", self.operators.show(&shape.start)),
+                    });
+                }
+                let start = Expr::parse(lexer, &self.operators)?;
+                writeln!(out, " => {}", self.operators.show(&start)).map_err(output_error(loc))?;
+                self.shape = Some(Shape {
+                    start,
+                    steps: Vec::new(),
+                });
+            }
+            "apply" => {
+                let name_loc = lexer.loc();
+                let name = expect_sym(lexer)?;
+                let shape = self.shape.as_mut().ok_or(NoqError::Command {
+                    loc,
+                    message: "no shape to apply rules to".to_string(),
+                })?;
+                let rule = find_rule(&self.rules, &name).ok_or_else(|| NoqError::Command {
+                    loc: name_loc,
+                    message: format!("unknown rule `{}`", name),
+                })?;
+                let strategy = parse_strategy(lexer)?;
+                let (expr, paths) = rule
+                    .apply_with_paths(&self.theories, shape.current(), &strategy)
+                    .map_err(|error| error.at(name_loc))?
+                    .ok_or_else(|| NoqError::NoMatch {
+                        loc: name_loc,
+                        rule: name.clone(),
+                        expr: shape.current().clone(),
+                    })?;
+                writeln!(out, " => {}", self.operators.show(&expr)).map_err(output_error(loc))?;
+                shape.steps.push(Step {
+                    rule_name: name,
+                    paths,
+                    expr,
+                });
+            }
+            "saturate" => {
+                let cost = lexer
+                    .next_sym_if(cost_function)
+                    .unwrap_or_else(|| Box::new(NodeCount));
+                let max_iterations = parse_number(lexer).unwrap_or(DEFAULT_MAX_ITERATIONS);
+                let shape = self.shape.as_mut().ok_or(NoqError::Command {
+                    loc,
+                    message: "no shape to saturate".to_string(),
+                })?;
+                let mut egraph = EGraph::default();
+                let root = egraph.add(shape.current());
+                let saturation = egraph
+                    .saturate(&self.rules, &self.theories, max_iterations, MAX_NODES)
+                    .map_err(|error| error.at(loc))?;
+                let (expr, cost) = egraph.extract(root, cost.as_ref());
+                let stop = match saturation {
+                    Saturation::Saturated(1) => "saturated after 1 iteration".to_string(),
+                    Saturation::Saturated(iterations) => {
+                        format!("saturated after {} iterations", iterations)
+                    }
+                    Saturation::IterationLimit => {
+                        format!("not saturated after {} iterations", max_iterations)
+                    }
+                    Saturation::NodeLimit => format!("not saturated, over {} nodes", MAX_NODES),
+                };
+                writeln!(
+                    out,
+                    " => {} (cost {}, {} classes, {})",
+                    self.operators.show(&expr),
+                    cost,
+                    egraph.classes_count(),
+                    stop
+                )
+                .map_err(output_error(loc))?;
+                if expr != *shape.current() {
+                    shape.steps.push(Step {
+                        rule_name: "saturate".to_string(),
+                        paths: vec![Path::new()],
+                        expr,
+                    });
+                }
+            }
+            "matches" => {
+                let name_loc = lexer.loc();
+                let name = expect_sym(lexer)?;
+                let shape = self.shape.as_ref().ok_or(NoqError::Command {
+                    loc,
+                    message: "no shape to match rules against".to_string(),
+                })?;
+                let rule = find_rule(&self.rules, &name).ok_or_else(|| NoqError::Command {
+                    loc: name_loc,
+                    message: format!("unknown rule `{}`", name),
+                })?;
+                let paths = rule
+                    .match_paths(&self.theories, shape.current())
+                    .map_err(|error| error.at(name_loc))?;
+                if paths.is_empty() {
+                    return Err(NoqError::NoMatch {
+                        loc: name_loc,
+                        rule: name,
+                        expr: shape.current().clone(),
+                    });
+                }
+                for (i, path) in paths.iter().enumerate() {
+                    let subexpr = shape.current().at(path).expect("match paths are valid");
+                    writeln!(
+                        out,
+                        " {}. {:?}: {}",
+                        i + 1,
+                        path,
+                        self.operators.show(subexpr)
+                    )
+                    .map_err(output_error(loc))?;
+                }
+            }
+            "normalize" => {
+                let shape = self.shape.as_mut().ok_or(NoqError::Command {
+                    loc,
+                    message: "no shape to normalize".to_string(),
+                })?;
+                let max_steps = parse_number(lexer).unwrap_or(DEFAULT_MAX_STEPS);
+                let normalization =
+                    normalize(&self.rules, &self.theories, shape.current(), max_steps)
+                        .map_err(|error| error.at(loc))?;
+                self.stats.add(&normalization.stats);
+                for step in normalization.steps {
+                    writeln!(
+                        out,
+                        " => {} ({})",
+                        self.operators.show(&step.expr),
+                        step.rule_name
+                    )
+                    .map_err(output_error(loc))?;
+                    shape.steps.push(step);
+                }
+                match normalization.stop {
+                    Stop::Normal => {}
+                    Stop::StepLimit => {
+                        return Err(NoqError::Command {
+                            loc,
+                            message: format!("no normal form after {} steps", max_steps),
+                        })
+                    }
+                    Stop::Cycle => {
+                        return Err(NoqError::Command {
+                            loc,
+                            message: format!(
+                                "rewriting {} loops forever",
+                                self.operators.show(shape.current())
+                            ),
+                        })
+                    }
+                }
+            }
+            "infix" => {
+                let symbol_loc = lexer.loc();
+                let symbol = expect_op(lexer)?;
+                if is_reserved_op(&symbol) {
+                    return Err(NoqError::Command {
+                        loc: symbol_loc,
+                        message: format!(
+                            "`{}` is reserved, it cannot be an infix operator",
+                            symbol
+                        ),
+                    });
+                }
+                let name = expect_sym(lexer)?;
+                let precedence_loc = lexer.loc();
+                let precedence = parse_number(lexer).ok_or(NoqError::Command {
+                    loc: precedence_loc,
+                    message: "expected operator precedence".to_string(),
+                })?;
+                let assoc_loc = lexer.loc();
+                let assoc = match expect_sym(lexer)?.as_str() {
+                    "left" => Assoc::Left,
+                    "right" => Assoc::Right,
+                    other => {
+                        return Err(NoqError::Command {
+                            loc: assoc_loc,
+                            message: format!("expected `left` or `right` but got `{}`", other),
+                        })
+                    }
+                };
+                self.operators.define(Operator {
+                    symbol,
+                    name,
+                    precedence,
+                    assoc,
+                });
+            }
+            command @ ("ac" | "comm") => {
+                let name = expect_sym(lexer)?;
+                let theory = match command {
+                    "ac" => Theory::AC,
+                    _ => Theory::C,
+                };
+                self.theories.insert(name, theory);
+            }
+            "fact" => {
+                let head = Expr::parse(lexer, &self.operators)?;
+                let mut clause = Clause::fact(head);
+                if matches!(lexer.peek(), Some(Token { kind: TokenKind::Sym(name), .. }) if name == "if")
+                {
+                    lexer.next();
+                    clause.body = parse_goals(lexer, &self.operators)?;
+                }
+                writeln!(out, "fact {}", self.show_clause(&clause)).map_err(output_error(loc))?;
+                self.program.push(clause);
+            }
+            "query" => {
+                let goals = parse_goals(lexer, &self.operators)?;
+                let max_depth = parse_number(lexer).unwrap_or(DEFAULT_MAX_DEPTH);
+                let mut vars = Vec::new();
+                for var in goals.iter().flat_map(Expr::vars) {
+                    if !vars.contains(&var) {
+                        vars.push(var);
+                    }
+                }
+                let mut solutions = solve(&self.program, &goals, max_depth);
+                let mut found = false;
+                for bindings in solutions.by_ref() {
+                    found = true;
+                    let shown: Vec<String> = vars
+                        .iter()
+                        .map(|var| format!("{} = {}", var, self.operators.show(&bindings[var])))
+                        .collect();
+                    if shown.is_empty() {
+                        writeln!(out, " yes").map_err(output_error(loc))?;
+                    } else {
+                        writeln!(out, " {}", shown.join(", ")).map_err(output_error(loc))?;
+                    }
+                }
+                if !found {
+                    writeln!(out, " no").map_err(output_error(loc))?;
+                }
+                if solutions.depth_limited() {
+                    writeln!(
+                        out,
+                        " (gave up on some goals at depth {}, there may be more solutions)",
+                        max_depth
+                    )
+                    .map_err(output_error(loc))?;
+                }
+            }
+            "tactic" => {
+                let name = expect_sym(lexer)?;
+                let tactic = self.parse_tactic(lexer)?;
+                match self
+                    .tactics
+                    .iter_mut()
+                    .find(|(tactic_name, _)| *tactic_name == name)
+                {
+                    Some(entry) => entry.1 = tactic,
+                    None => self.tactics.push((name, tactic)),
+                }
+            }
+            "run" => {
+                let tactic = self.parse_tactic(lexer)?;
+                let shape = self.shape.as_mut().ok_or(NoqError::Command {
+                    loc,
+                    message: "no shape to run a tactic on".to_string(),
+                })?;
+                let (_, steps) = tactic
+                    .apply(&self.theories, shape.current())
+                    .map_err(|error| error.at(loc))?
+                    .ok_or_else(|| NoqError::Command {
+                        loc,
+                        message: format!(
+                            "tactic failed on {}",
+                            self.operators.show(shape.current())
+                        ),
+                    })?;
+                for step in steps {
+                    writeln!(
+                        out,
+                        " => {} ({})",
+                        self.operators.show(&step.expr),
+                        step.rule_name
+                    )
+                    .map_err(output_error(loc))?;
+                    shape.steps.push(step);
+                }
+            }
+            "unify" => {
+                // Not a rule, both sides can have variables of their own.
+                let a = Expr::parse(lexer, &self.operators)?;
+                expect_token(lexer, TokenKind::Equals)?;
+                let b = Expr::parse(lexer, &self.operators)?;
+                let bindings = unify(&a, &b).ok_or_else(|| NoqError::Command {
+                    loc,
+                    message: format!(
+                        "{} and {} do not unify",
+                        self.operators.show(&a),
+                        self.operators.show(&b)
+                    ),
+                })?;
+                let mut bindings: Vec<_> = bindings.into_iter().collect();
+                bindings.sort_by(|(name1, _), (name2, _)| name1.cmp(name2));
+                for (name, value) in bindings {
+                    writeln!(out, " {} = {}", name, self.operators.show(&value))
+                        .map_err(output_error(loc))?;
+                }
+            }
+            "confluence" => {
+                let max_steps = parse_number(lexer).unwrap_or(DEFAULT_MAX_STEPS);
+                let divergences =
+                    non_joinable_pairs(&self.rules, max_steps).map_err(|error| error.at(loc))?;
+                for divergence in &divergences {
+                    let pair = &divergence.pair;
+                    writeln!(
+                        out,
+                        " {} inside {} at {:?}: {}",
+                        pair.inner,
+                        pair.outer,
+                        pair.path,
+                        self.operators.show(&pair.overlap)
+                    )
+                    .map_err(output_error(loc))?;
+                    writeln!(
+                        out,
+                        "   => {} =>* {}",
+                        self.operators.show(&pair.left),
+                        self.operators.show(&divergence.left_normal)
+                    )
+                    .map_err(output_error(loc))?;
+                    writeln!(
+                        out,
+                        "   => {} =>* {}",
+                        self.operators.show(&pair.right),
+                        self.operators.show(&divergence.right_normal)
+                    )
+                    .map_err(output_error(loc))?;
+                }
+                if !divergences.is_empty() {
+                    return Err(NoqError::Command {
+                        loc,
+                        message: format!("{} critical pairs are not joinable", divergences.len()),
+                    });
+                }
+                writeln!(out, " all critical pairs are joinable").map_err(output_error(loc))?;
+            }
+            "complete" => {
+                let order_loc = lexer.loc();
+                let order = parse_order(lexer, &self.operators)?;
+                let max_rules = parse_number(lexer).unwrap_or(DEFAULT_MAX_RULES);
+                check_order(
+                    order.as_ref(),
+                    self.rules.iter().map(|(name, rule)| (name, rule)),
+                    order_loc,
+                )?;
+                let rules =
+                    match complete(&self.rules, order.as_ref(), max_rules, DEFAULT_MAX_STEPS) {
+                        Ok(rules) => rules,
+                        Err(CompletionError::Unorientable { name, lhs, rhs }) => {
+                            return Err(NoqError::Command {
+                                loc,
+                                message: format!(
+                                    "could not orient {}: {} = {}",
+                                    name,
+                                    self.operators.show(&lhs),
+                                    self.operators.show(&rhs)
+                                ),
+                            })
+                        }
+                        Err(CompletionError::RuleLimit(max_rules)) => {
+                            return Err(NoqError::Command {
+                                loc,
+                                message: format!("gave up after {} rules", max_rules),
+                            })
+                        }
+                        Err(CompletionError::Noq(error)) => return Err(error.at(loc)),
+                        Err(CompletionError::Conditional(name)) => {
+                            return Err(NoqError::Command {
+                                loc,
+                                message: format!("cannot complete conditional rule `{}`", name),
+                            })
+                        }
+                        Err(CompletionError::StepLimit(expr)) => {
+                            return Err(NoqError::Command {
+                                loc,
+                                message: format!(
+                                    "no normal form for {}",
+                                    self.operators.show(&expr)
+                                ),
+                            })
+                        }
+                    };
+                for (name, rule) in &rules {
+                    writeln!(out, "rule {}: {}", name, self.operators.show_rule(rule))
+                        .map_err(output_error(loc))?;
+                }
+                self.rules = rules;
+            }
+            "order" => {
+                let order_loc = lexer.loc();
+                let order = parse_order(lexer, &self.operators)?;
+                check_order(
+                    order.as_ref(),
+                    self.rules.iter().map(|(name, rule)| (name, rule)),
+                    order_loc,
+                )?;
+                let mut unoriented = 0;
+                for (name, rule) in &self.rules {
+                    let problem = match orientation(order.as_ref(), rule) {
+                        Orientation::Forward => continue,
+                        Orientation::Backward => "makes expressions bigger",
+                        Orientation::Unorientable => "cannot be oriented",
+                    };
+                    writeln!(
+                        out,
+                        " {}: {} {}",
+                        name,
+                        self.operators.show_rule(rule),
+                        problem
+                    )
+                    .map_err(output_error(loc))?;
+                    unoriented += 1;
+                }
+                if unoriented > 0 {
+                    return Err(NoqError::Command {
+                        loc,
+                        message: format!("{} rules are not decreasing", unoriented),
+                    });
+                }
+                self.order = Some(order);
+            }
+            "stats" => {
+                writeln!(out,
+                    " {} lookups in the rule index, {} candidate rules tried instead of {}, {} matched",
+                    self.stats.lookups,
+                    self.stats.candidates,
+                    self.stats.without_index,
+                    self.stats.matches
+                ).map_err(output_error(loc))?;
+            }
+            "undo" => {
+                let shape = self.shape.as_mut().ok_or(NoqError::Command {
+                    loc,
+                    message: "no shape to undo".to_string(),
+                })?;
+                if shape.steps.pop().is_none() {
+                    return Err(NoqError::Command {
+                        loc,
+                        message: "nothing to undo".to_string(),
+                    });
+                }
+                writeln!(out, " => {}", self.operators.show(shape.current()))
+                    .map_err(output_error(loc))?;
+            }
+            "history" => {
+                let shape = self.shape.as_ref().ok_or(NoqError::Command {
+                    loc,
+                    message: "no shape to show".to_string(),
+                })?;
+                writeln!(out, " {}", self.operators.show(&shape.start))
+                    .map_err(output_error(loc))?;
+                for step in &shape.steps {
+                    writeln!(
+                        out,
+                        " => {} ({})",
+                        self.operators.show(&step.expr),
+                        step.rule_name
+                    )
+                    .map_err(output_error(loc))?;
+                }
+            }
+            "done" => {
+                let shape = self.shape.take().ok_or(NoqError::Command {
+                    loc,
+                    message: "no shape to finish".to_string(),
+                })?;
+                writeln!(
+                    out,
+                    "{} => {}",
+                    self.operators.show(&shape.start),
+                    self.operators.show(shape.current())
+                )
+                .map_err(output_error(loc))?;
+                self.finished = Some(shape);
+            }
+            "draw" => {
+                let shape = self.shape.as_ref().ok_or(NoqError::Command {
+                    loc,
+                    message: "no shape to draw".to_string(),
+                })?;
+                let style_loc = lexer.loc();
+                match expect_sym(lexer)?.as_str() {
+                    "tree" => write!(out, "{}", render::tree(shape.current(), &self.operators))
+                        .map_err(output_error(loc))?,
+                    "dot" => {
+                        let name_loc = lexer.loc();
+                        let mut matches = Vec::new();
+                        if let Some(rule) = lexer.next_sym_if(|name| find_rule(&self.rules, name)) {
+                            matches = render::rule_matches(rule, &self.theories, shape.current())
+                                .map_err(|error| error.at(name_loc))?;
+                        }
+                        write!(
+                            out,
+                            "{}",
+                            render::dot(shape.current(), &self.operators, &matches)
+                        )
+                        .map_err(output_error(loc))?;
+                    }
+                    style => {
+                        return Err(NoqError::Command {
+                            loc: style_loc,
+                            message: format!("expected `tree` or `dot` but got `{}`", style),
+                        })
+                    }
+                }
+            }
+            "export" => {
+                let format_loc = lexer.loc();
+                let format_name = expect_sym(lexer)?;
+                let format = Format::by_name(&format_name).ok_or_else(|| NoqError::Command {
+                    loc: format_loc,
+                    message: format!(
+                        "expected `text`, `markdown` or `json` but got `{}`",
+                        format_name
+                    ),
+                })?;
+                // The shape in progress, or else the last one that was done.
+                let shape =
+                    self.shape
+                        .as_ref()
+                        .or(self.finished.as_ref())
+                        .ok_or(NoqError::Command {
+                            loc,
+                            message: "no shape to export".to_string(),
+                        })?;
+                write!(
+                    out,
+                    "{}",
+                    proof::export(&shape.start, &shape.steps, &self.operators, format)
+                )
+                .map_err(output_error(loc))?;
+            }
+            unknown => {
+                return Err(NoqError::Command {
+                    loc,
+                    message: format!("unknown command `{}`", unknown),
+                })
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn shape_steps_can_be_undone() {
+    let mut context = Context::default();
+    let mut run =
+        |source: &str| context.process_command(&mut Lexer::new(source.chars()), &mut Vec::new());
+    run("rule id f(X) = X").unwrap();
+    run("shape g(f(a))").unwrap();
+    assert_eq!(
+        run("apply nope").unwrap_err().to_string(),
+        "1:7: unknown rule `nope`"
+    );
+    run("undo").unwrap_err();
+    run("done").unwrap();
+    run("undo").unwrap_err();
+}
+
+impl Context {
+    /// Runs every command of `source`, stopping at the first one that fails.
+    /// A shape that is not done by the end is an error too.
+    pub fn run_script(&mut self, source: &str, out: &mut impl Write) -> Result<(), NoqError> {
+        let mut lexer = Lexer::new(source.chars());
+        while lexer.peek().is_some() {
+            self.process_command(&mut lexer, out)?;
+        }
+        if let Some(shape) = &self.shape {
+            return Err(NoqError::Command {
+                loc: lexer.loc(),
+                message: format!("shape {} is not done", self.operators.show(&shape.start)),
+            });
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn script_reports_failed_steps() {
+    let script = "
+        # Swapping twice gets us back where we started.
+        rule swap swap(pair(A, B)) = pair(B, A)
+        shape swap(swap(pair(a, b)))
+            apply swap at 0
+            apply swap
+        done
+    ";
+    assert_eq!(
+        Context::default().run_script(script, &mut Vec::new()),
+        Ok(())
+    );
+
+    let error = Context::default()
+        .run_script(
+            "rule swap swap(pair(A, B)) = pair(B, A)\nshape pair(a, b)\n  apply swap\ndone",
+            &mut Vec::new(),
+        )
+        .unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "3:9: rule `swap` does not match pair(a, b)"
+    );
+
+    let error = Context::default()
+        .run_script("shape a", &mut Vec::new())
+        .unwrap_err();
+    assert_eq!(error.to_string(), "1:8: shape a is not done");
+    let error = Context::default()
+        .run_script("infix & and 1 left\nshape a & b", &mut Vec::new())
+        .unwrap_err();
+    assert_eq!(error.to_string(), "2:12: shape a & b is not done");
+}
+
+#[test]
+fn script_errors_point_at_command() {
+    let mut context = Context::default();
+    let error = context
+        .run_script(
+            "rule wrap wrap(F) = F(a)\nshape wrap(g(b))\n  normalize",
+            &mut Vec::new(),
+        )
+        .unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "3:3: functor variable `F` is bound to g(b), which is not a symbol"
+    );
+}
+
+#[test]
+fn kbo_weights_are_checked() {
+    let run = |script: &str| {
+        Context::default()
+            .run_script(script, &mut Vec::new())
+            .unwrap_err()
+            .to_string()
+    };
+    assert_eq!(
+        run("order kbo(f(0), g(0))"),
+        "1:7: only one functor can weigh 0, not both `f` and `g`"
+    );
+    assert_eq!(
+        run("rule r f(X, Y) = X\norder kbo(f(0), g)"),
+        "2:7: cannot order rule `r`: `f` weighs 0, so it has to take one argument, not 2"
+    );
+}
+
+#[test]
+fn reserved_operators_cannot_be_defined() {
+    for symbol in ["==", "!=", "<=>"] {
+        let error = Context::default()
+            .run_script(&format!("infix {} eq 1 left", symbol), &mut Vec::new())
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "1:7: `{}` is reserved, it cannot be an infix operator",
+                symbol
+            )
+        );
+    }
+}
+
+#[test]
+fn optional_arguments_leave_the_next_command_alone() {
+    let script = "
+        rule double double(X) <=> X + X
+        shape double(a)
+            saturate
+            draw dot
+            draw dot double
+        done
+    ";
+    assert_eq!(
+        Context::default().run_script(script, &mut Vec::new()),
+        Ok(())
+    );
+}
+
+#[test]
+fn rewrite_modulo_theories() {
+    let mut context = Context::default();
+    let mut run =
+        |source: &str| context.process_command(&mut Lexer::new(source.chars()), &mut Vec::new());
+    run("rule zero_right add(X, zero) = X").unwrap();
+    run("rule cancel add(X, neg(X)) = zero").unwrap();
+    run("shape a + (zero + (b + neg(a)))").unwrap();
+    run("apply zero_right").unwrap_err();
+    run("ac add").unwrap();
+    run("apply zero_right").unwrap();
+    run("apply cancel").unwrap();
+    assert_eq!(
+        context.shape.as_ref().unwrap().current().to_string(),
+        "zero + b"
+    );
+    let normalization = normalize(
+        &context.rules,
+        &context.theories,
+        &"a + (zero + (b + neg(a)))".parse().unwrap(),
+        DEFAULT_MAX_STEPS,
+    )
+    .unwrap();
+    assert_eq!(normalization.stop, Stop::Normal);
+    let result = &normalization.steps.last().unwrap().expr;
+    assert_eq!(result.to_string(), "b");
+}
+
+#[test]
+fn commands_write_what_they_did() {
+    let mut out = Vec::new();
+    let script = "rule id f(X) = X\nshape g(f(a))\n  apply id\ndone";
+    Context::default().run_script(script, &mut out).unwrap();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "rule id: f(X) = X\n => g(f(a))\n => g(a)\ng(f(a)) => g(a)\n"
+    );
+}
@@ -0,0 +1,147 @@
+use crate::{
+    normal_form, substitute_bindings, unify::rename_vars, unify::unify, Expr, NoqError, Path, Rule,
+};
+
+/// Two different ways of rewriting `overlap`: with `outer` at the root, giving
+/// `left`, and with `inner` at `path`, giving `right`.
+#[derive(Debug)]
+pub struct CriticalPair {
+    /// The name of the rule applied at the root.
+    pub outer: String,
+    /// The name of the rule applied inside it.
+    pub inner: String,
+    /// Where `inner` applies in `overlap`.
+    pub path: Path,
+    /// The most general expression both rules rewrite.
+    pub overlap: Expr,
+    /// `overlap` rewritten by `outer`.
+    pub left: Expr,
+    /// `overlap` rewritten by `inner`.
+    pub right: Expr,
+}
+
+/// Every critical pair between `rules`, including those of a rule with
+/// itself at a path other than the root.
+pub fn critical_pairs(rules: &[(String, Rule)]) -> Result<Vec<CriticalPair>, NoqError> {
+    let mut pairs = Vec::new();
+    for (outer_name, outer) in rules {
+        for (inner_name, inner) in rules {
+            pairs.extend(overlaps(outer_name, outer, inner_name, inner)?);
+        }
+    }
+    Ok(pairs)
+}
+
+/// Critical pairs from unifying the head of `inner` with the subexpressions of
+/// the head of `outer`.
+pub fn overlaps(
+    outer_name: &str,
+    outer: &Rule,
+    inner_name: &str,
+    inner: &Rule,
+) -> Result<Vec<CriticalPair>, NoqError> {
+    // Rename the inner rule apart, it might share variable names with the
+    // outer one or even be the same rule.
+    let inner_head = rename_vars(&inner.head, "'");
+    let inner_body = rename_vars(&inner.body, "'");
+    let mut pairs = Vec::new();
+    for path in outer.head.paths() {
+        // A rule trivially overlaps with itself at the root.
+        if path.is_empty() && outer_name == inner_name {
+            continue;
+        }
+        let subexpr = outer.head.at(&path).unwrap();
+        if matches!(subexpr, Expr::Var(_)) {
+            continue;
+        }
+        let Some(bindings) = unify(subexpr, &inner_head) else {
+            continue;
+        };
+        let overlap = substitute_bindings(&bindings, &outer.head)?;
+        let left = substitute_bindings(&bindings, &outer.body)?;
+        let right = overlap
+            .replace_at(&path, substitute_bindings(&bindings, &inner_body)?)
+            .unwrap();
+        pairs.push(CriticalPair {
+            outer: outer_name.to_string(),
+            inner: inner_name.to_string(),
+            path,
+            overlap,
+            left,
+            right,
+        });
+    }
+    Ok(pairs)
+}
+
+/// A critical pair whose sides normalize to different expressions, or do not
+/// normalize at all within the step limit.
+#[derive(Debug)]
+pub struct Divergence {
+    /// The pair.
+    pub pair: CriticalPair,
+    /// Where its left side ended up.
+    pub left_normal: Expr,
+    /// Where its right side ended up.
+    pub right_normal: Expr,
+}
+
+/// The critical pairs of `rules` that are not joinable, giving up on a side
+/// after `max_steps` steps. None means the rules are locally confluent.
+pub fn non_joinable_pairs(
+    rules: &[(String, Rule)],
+    max_steps: usize,
+) -> Result<Vec<Divergence>, NoqError> {
+    let mut divergences = Vec::new();
+    for pair in critical_pairs(rules)? {
+        let left = normal_form(rules, &pair.left, max_steps)?;
+        let right = normal_form(rules, &pair.right, max_steps)?;
+        match (left, right) {
+            (Ok(left), Ok(right)) if left == right => {}
+            (Ok(left_normal) | Err(left_normal), Ok(right_normal) | Err(right_normal)) => {
+                divergences.push(Divergence {
+                    pair,
+                    left_normal,
+                    right_normal,
+                })
+            }
+        }
+    }
+    Ok(divergences)
+}
+
+#[cfg(test)]
+fn parse_rules(rules: &[(&str, &str)]) -> Vec<(String, Rule)> {
+    rules
+        .iter()
+        .map(|(name, rule)| (name.to_string(), rule.parse().unwrap()))
+        .collect()
+}
+
+#[test]
+fn critical_pairs_of_overlapping_rules() {
+    let rules = parse_rules(&[("ff", "f(f(X)) = g(X)")]);
+    let pairs = critical_pairs(&rules).unwrap();
+    assert_eq!(pairs.len(), 1);
+    let pair = &pairs[0];
+    assert_eq!(pair.path, vec![0]);
+    assert_eq!(pair.overlap.to_string(), "f(f(f(X')))");
+    assert_eq!(pair.left.to_string(), "g(f(X'))");
+    assert_eq!(pair.right.to_string(), "f(g(X'))");
+
+    let divergences = non_joinable_pairs(&rules, 100).unwrap();
+    assert_eq!(divergences.len(), 1);
+}
+
+#[test]
+fn joinable_critical_pairs() {
+    let rules = parse_rules(&[("left", "and(true, X) = X"), ("right", "and(X, true) = X")]);
+    assert_eq!(critical_pairs(&rules).unwrap().len(), 2);
+    assert!(non_joinable_pairs(&rules, 100).unwrap().is_empty());
+
+    let rules = parse_rules(&[("a", "f(a) = b"), ("c", "f(X) = c")]);
+    let divergences = non_joinable_pairs(&rules, 100).unwrap();
+    assert_eq!(divergences.len(), 2);
+    assert_eq!(divergences[0].left_normal.to_string(), "b");
+    assert_eq!(divergences[0].right_normal.to_string(), "c");
+}
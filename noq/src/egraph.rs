@@ -0,0 +1,590 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{
+    is_var_name,
+    number::{self, Number},
+    theory::{Theories, Theory},
+    Expr, Guard, NoqError, Rule,
+};
+
+/// A class of an `EGraph`. Merged classes keep their ids, `EGraph::find`
+/// gives the class they were merged into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ClassId(u32);
+
+/// An expression whose arguments are classes of equivalent expressions rather
+/// than expressions.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ENode {
+    /// A constant.
+    Sym(String),
+    /// Variables of the expressions put in the graph are just names here.
+    Var(String),
+    /// A number literal.
+    Num(Number),
+    /// A functor applied to classes.
+    Fun(String, Vec<ClassId>),
+}
+
+// One binding per variable name. A functor variable stands for the constant
+// named like the functor it matched, as in `theory::pattern_match`, which need
+// not be in the graph.
+#[derive(Debug, Clone)]
+enum EBound {
+    Class(ClassId),
+    Functor(String),
+}
+
+type EBindings = HashMap<String, EBound>;
+
+/// How saturation ended.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Saturation {
+    /// No rule adds anything new anymore, after the given number of
+    /// iterations.
+    Saturated(usize),
+    /// Rules still added something after the maximum number of iterations.
+    IterationLimit,
+    /// The graph grew past the maximum number of nodes.
+    NodeLimit,
+}
+
+/// Classes of equivalent expressions, sharing subexpressions like `Arena`.
+/// Rewriting merges the classes of both sides instead of replacing one with the
+/// other, so rules that conflict or loop under `normalize` lose nothing here,
+/// and the cheapest of all the expressions found is extracted at the end.
+#[derive(Debug, Default)]
+pub struct EGraph {
+    // Union-find over class ids. Merged classes point to the class they were
+    // merged into, canonical classes to themselves.
+    parents: Vec<ClassId>,
+    // The nodes of every canonical class.
+    classes: BTreeMap<ClassId, Vec<ENode>>,
+    // Canonical nodes to their class, complete after `rebuild`.
+    memo: HashMap<ENode, ClassId>,
+}
+
+/// How `extract` ranks the expressions of a class.
+pub trait CostFunction {
+    /// The cost of `node` given the costs of its arguments. It has to be
+    /// greater than every argument cost, otherwise the cheapest expression
+    /// could contain itself.
+    fn cost(&self, node: &ENode, arg_costs: &[usize]) -> usize;
+}
+
+/// The number of nodes in the expression, the default.
+pub struct NodeCount;
+
+impl CostFunction for NodeCount {
+    fn cost(&self, _node: &ENode, arg_costs: &[usize]) -> usize {
+        arg_costs
+            .iter()
+            .fold(1, |sum, &cost| sum.saturating_add(cost))
+    }
+}
+
+/// The depth of the expression, preferring flat ones to small ones.
+pub struct Depth;
+
+impl CostFunction for Depth {
+    fn cost(&self, _node: &ENode, arg_costs: &[usize]) -> usize {
+        1 + arg_costs.iter().copied().max().unwrap_or(0)
+    }
+}
+
+/// The cost function called `name` in the REPL, `size` or `depth`.
+pub fn cost_function(name: &str) -> Option<Box<dyn CostFunction>> {
+    match name {
+        "size" => Some(Box::new(NodeCount)),
+        "depth" => Some(Box::new(Depth)),
+        _ => None,
+    }
+}
+
+impl EGraph {
+    /// The canonical class `id` was merged into.
+    pub fn find(&self, mut id: ClassId) -> ClassId {
+        while self.parents[id.0 as usize] != id {
+            id = self.parents[id.0 as usize];
+        }
+        id
+    }
+
+    /// How many classes there are, counting merged ones once.
+    pub fn classes_count(&self) -> usize {
+        self.classes.len()
+    }
+
+    /// How many nodes there are in all the classes.
+    pub fn nodes_count(&self) -> usize {
+        self.classes.values().map(Vec::len).sum()
+    }
+
+    fn canonicalize(&self, node: &ENode) -> ENode {
+        match node {
+            ENode::Fun(name, args) => ENode::Fun(
+                name.clone(),
+                args.iter().map(|&arg| self.find(arg)).collect(),
+            ),
+            _ => node.clone(),
+        }
+    }
+
+    fn add_node(&mut self, node: ENode) -> ClassId {
+        let node = self.canonicalize(&node);
+        if let Some(&id) = self.memo.get(&node) {
+            return self.find(id);
+        }
+        let id = ClassId(self.parents.len() as u32);
+        self.parents.push(id);
+        self.classes.insert(id, vec![node.clone()]);
+        self.memo.insert(node, id);
+        id
+    }
+
+    /// Adds `expr` and its subexpressions, returning its class.
+    pub fn add(&mut self, expr: &Expr) -> ClassId {
+        let node = match expr {
+            Expr::Sym(name) => ENode::Sym(name.clone()),
+            Expr::Var(name) => ENode::Var(name.clone()),
+            Expr::Num(number) => ENode::Num(*number),
+            Expr::Fun(name, args) => {
+                let args = args.iter().map(|arg| self.add(arg)).collect();
+                ENode::Fun(name.clone(), args)
+            }
+        };
+        self.add_node(node)
+    }
+
+    /// Merges the classes of `a` and `b`. Returns whether the classes were
+    /// different.
+    pub fn union(&mut self, a: ClassId, b: ClassId) -> bool {
+        let (a, b) = (self.find(a), self.find(b));
+        if a == b {
+            return false;
+        }
+        let (root, child) = (a.min(b), a.max(b));
+        self.parents[child.0 as usize] = root;
+        let nodes = self.classes.remove(&child).expect("child is canonical");
+        self.classes
+            .get_mut(&root)
+            .expect("root is canonical")
+            .extend(nodes);
+        true
+    }
+
+    // Makes every node canonical again after unions, merging the classes that
+    // end up with equal nodes: applications of the same functor to equal
+    // arguments are equal.
+    fn rebuild(&mut self) {
+        loop {
+            let mut memo = HashMap::new();
+            let mut merges = Vec::new();
+            for (id, nodes) in std::mem::take(&mut self.classes) {
+                let mut nodes: Vec<ENode> =
+                    nodes.iter().map(|node| self.canonicalize(node)).collect();
+                nodes.sort();
+                nodes.dedup();
+                for node in &nodes {
+                    match memo.get(node) {
+                        Some(&other) => merges.push((other, id)),
+                        None => {
+                            memo.insert(node.clone(), id);
+                        }
+                    }
+                }
+                self.classes.insert(id, nodes);
+            }
+            self.memo = memo;
+            if merges.is_empty() {
+                return;
+            }
+            for (a, b) in merges {
+                self.union(a, b);
+            }
+        }
+    }
+
+    // Every way of matching `pattern` against some expression in `class`.
+    fn ematch(
+        &self,
+        pattern: &Expr,
+        class: ClassId,
+        bindings: EBindings,
+        results: &mut Vec<EBindings>,
+    ) {
+        let class = self.find(class);
+        let nodes = &self.classes[&class];
+        match pattern {
+            Expr::Var(name) => match bindings.get(name) {
+                Some(EBound::Class(bound)) if self.find(*bound) != class => {}
+                Some(EBound::Functor(functor)) if !nodes.contains(&ENode::Sym(functor.clone())) => {
+                }
+                Some(_) => results.push(bindings),
+                None => {
+                    let mut bindings = bindings;
+                    bindings.insert(name.clone(), EBound::Class(class));
+                    results.push(bindings);
+                }
+            },
+            Expr::Sym(name) => {
+                if nodes.contains(&ENode::Sym(name.clone())) {
+                    results.push(bindings);
+                }
+            }
+            Expr::Num(number) => {
+                if nodes.contains(&ENode::Num(*number)) {
+                    results.push(bindings);
+                }
+            }
+            Expr::Fun(name, args) => {
+                for node in nodes {
+                    let ENode::Fun(node_name, node_args) = node else {
+                        continue;
+                    };
+                    if node_args.len() != args.len() {
+                        continue;
+                    }
+                    let mut bindings = bindings.clone();
+                    if is_var_name(name) {
+                        match bindings.get(name) {
+                            Some(EBound::Class(bound))
+                                if !self.classes[&self.find(*bound)]
+                                    .contains(&ENode::Sym(node_name.clone())) =>
+                            {
+                                continue
+                            }
+                            Some(EBound::Functor(bound)) if bound != node_name => continue,
+                            Some(_) => {}
+                            None => {
+                                bindings.insert(name.clone(), EBound::Functor(node_name.clone()));
+                            }
+                        }
+                    } else if name != node_name {
+                        continue;
+                    }
+                    let mut partial = vec![bindings];
+                    for (arg, &node_arg) in args.iter().zip(node_args) {
+                        let mut next = Vec::new();
+                        for bindings in partial {
+                            self.ematch(arg, node_arg, bindings, &mut next);
+                        }
+                        partial = next;
+                    }
+                    results.extend(partial);
+                }
+            }
+        }
+    }
+
+    // Same as `crate::substitute_bindings`, adding the result to the graph.
+    fn instantiate(&mut self, expr: &Expr, bindings: &EBindings) -> Result<ClassId, NoqError> {
+        match expr {
+            Expr::Var(name) => match bindings.get(name) {
+                Some(EBound::Class(class)) => Ok(*class),
+                Some(EBound::Functor(functor)) => Ok(self.add_node(ENode::Sym(functor.clone()))),
+                None => Ok(self.add(expr)),
+            },
+            Expr::Fun(name, args) => {
+                let name = match bindings.get(name) {
+                    Some(EBound::Functor(functor)) => functor.clone(),
+                    Some(&EBound::Class(class)) => {
+                        let class = self.find(class);
+                        let functor = self.classes[&class].iter().find_map(|node| match node {
+                            ENode::Sym(functor) | ENode::Var(functor) => Some(functor.clone()),
+                            _ => None,
+                        });
+                        match functor {
+                            Some(functor) => functor,
+                            None => {
+                                return Err(NoqError::FunctorNotSymbol {
+                                    loc: None,
+                                    name: name.clone(),
+                                    value: self.extract(class, &NodeCount).0,
+                                })
+                            }
+                        }
+                    }
+                    None => name.clone(),
+                };
+                let mut new_args = Vec::new();
+                for arg in args {
+                    new_args.push(self.instantiate(arg, bindings)?);
+                }
+                Ok(self.add_node(ENode::Fun(name, new_args)))
+            }
+            Expr::Sym(_) | Expr::Num(_) => Ok(self.add(expr)),
+        }
+    }
+
+    // Guards compare classes: `==` holds if both sides are known to be
+    // equal so far, `!=` if they are not.
+    fn satisfies_guards(&mut self, rule: &Rule, bindings: &EBindings) -> Result<bool, NoqError> {
+        for guard in &rule.guards {
+            let holds = match guard {
+                Guard::Equal(a, b) | Guard::NotEqual(a, b) => {
+                    let a = self.instantiate(a, bindings)?;
+                    let b = self.instantiate(b, bindings)?;
+                    (self.find(a) == self.find(b)) == matches!(guard, Guard::Equal(_, _))
+                }
+                Guard::Number(a) => {
+                    let a = self.instantiate(a, bindings)?;
+                    self.number(a).is_some()
+                }
+            };
+            if !holds {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    fn number(&self, class: ClassId) -> Option<Number> {
+        self.classes[&self.find(class)]
+            .iter()
+            .find_map(|node| match node {
+                ENode::Num(number) => Some(*number),
+                _ => None,
+            })
+    }
+
+    // Merges every arithmetic operator applied to two numbers with its
+    // value, same as the built-in evaluation of `normalize`.
+    fn fold_constants(&mut self) -> bool {
+        let mut folds = Vec::new();
+        for (&class, nodes) in &self.classes {
+            for node in nodes {
+                let ENode::Fun(name, args) = node else {
+                    continue;
+                };
+                let [a, b] = args[..] else {
+                    continue;
+                };
+                if let (Some(a), Some(b)) = (self.number(a), self.number(b)) {
+                    folds.extend(number::evaluate(name, a, b).map(|value| (class, value)));
+                }
+            }
+        }
+        let mut changed = false;
+        for (class, value) in folds {
+            let value = self.add_node(ENode::Num(value));
+            changed |= self.union(class, value);
+        }
+        changed
+    }
+
+    /// Applies `rules` everywhere at once, reversible ones in both directions,
+    /// until nothing changes or one of the limits is reached. Functors with
+    /// theories get their equations as extra rules.
+    pub fn saturate(
+        &mut self,
+        rules: &[(String, Rule)],
+        theories: &Theories,
+        max_iterations: usize,
+        max_nodes: usize,
+    ) -> Result<Saturation, NoqError> {
+        let mut directions = Vec::new();
+        for (_, rule) in rules {
+            directions.push(rule.clone());
+            if rule.reversible {
+                directions.push(Rule {
+                    head: rule.body.clone(),
+                    body: rule.head.clone(),
+                    guards: rule.guards.clone(),
+                    reversible: true,
+                });
+            }
+        }
+        directions.extend(theory_rules(theories));
+
+        self.rebuild();
+        for iteration in 0..max_iterations {
+            let mut matches = Vec::new();
+            for (i, rule) in directions.iter().enumerate() {
+                for &class in self.classes.keys() {
+                    let mut results = Vec::new();
+                    self.ematch(&rule.head, class, EBindings::default(), &mut results);
+                    matches.extend(results.into_iter().map(|bindings| (i, class, bindings)));
+                }
+            }
+            // Guards are checked before any class is merged, so they see
+            // the graph as it was when matching.
+            let mut applicable = Vec::new();
+            for (i, class, bindings) in matches {
+                if self.satisfies_guards(&directions[i], &bindings)? {
+                    applicable.push((i, class, bindings));
+                }
+            }
+            let mut changed = false;
+            for (i, class, bindings) in applicable {
+                let body = self.instantiate(&directions[i].body, &bindings)?;
+                changed |= self.union(class, body);
+            }
+            changed |= self.fold_constants();
+            self.rebuild();
+            if !changed {
+                return Ok(Saturation::Saturated(iteration + 1));
+            }
+            if self.nodes_count() > max_nodes {
+                return Ok(Saturation::NodeLimit);
+            }
+        }
+        Ok(Saturation::IterationLimit)
+    }
+
+    /// The cheapest expression in the class of `id` by `cost`, and its cost.
+    pub fn extract(&self, id: ClassId, cost: &dyn CostFunction) -> (Expr, usize) {
+        let mut best: HashMap<ClassId, (usize, &ENode)> = HashMap::new();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (&class, nodes) in &self.classes {
+                for node in nodes {
+                    let arg_costs: Option<Vec<usize>> = match node {
+                        ENode::Fun(_, args) => args
+                            .iter()
+                            .map(|&arg| best.get(&self.find(arg)).map(|&(cost, _)| cost))
+                            .collect(),
+                        _ => Some(Vec::new()),
+                    };
+                    let Some(arg_costs) = arg_costs else {
+                        continue;
+                    };
+                    let node_cost = cost.cost(node, &arg_costs);
+                    if best
+                        .get(&class)
+                        .is_none_or(|&(best_cost, _)| node_cost < best_cost)
+                    {
+                        best.insert(class, (node_cost, node));
+                        changed = true;
+                    }
+                }
+            }
+        }
+
+        fn build(
+            egraph: &EGraph,
+            class: ClassId,
+            best: &HashMap<ClassId, (usize, &ENode)>,
+        ) -> Expr {
+            match best[&egraph.find(class)].1 {
+                ENode::Sym(name) => Expr::Sym(name.clone()),
+                ENode::Var(name) => Expr::Var(name.clone()),
+                ENode::Num(number) => Expr::Num(*number),
+                ENode::Fun(name, args) => Expr::Fun(
+                    name.clone(),
+                    args.iter().map(|&arg| build(egraph, arg, best)).collect(),
+                ),
+            }
+        }
+
+        let id = self.find(id);
+        (build(self, id, &best), best[&id].0)
+    }
+}
+
+// The equations of `theories` as rules, in both directions where needed.
+fn theory_rules(theories: &Theories) -> Vec<Rule> {
+    let var = |name: &str| Expr::Var(name.to_string());
+    let rule = |head, body| Rule {
+        head,
+        body,
+        guards: Vec::new(),
+        reversible: false,
+    };
+    let mut theories: Vec<_> = theories.iter().collect();
+    theories.sort_by_key(|(name, _)| *name);
+    let mut rules = Vec::new();
+    for (name, theory) in theories {
+        let f = |a, b| Expr::Fun(name.clone(), vec![a, b]);
+        rules.push(rule(f(var("X"), var("Y")), f(var("Y"), var("X"))));
+        if *theory == Theory::AC {
+            let left = f(f(var("X"), var("Y")), var("Z"));
+            let right = f(var("X"), f(var("Y"), var("Z")));
+            rules.push(rule(left.clone(), right.clone()));
+            rules.push(rule(right, left));
+        }
+    }
+    rules
+}
+
+#[cfg(test)]
+fn optimize(rules: &[&str], theories: &Theories, expr: &str, cost: &dyn CostFunction) -> String {
+    let rules: Vec<(String, Rule)> = rules
+        .iter()
+        .enumerate()
+        .map(|(i, rule)| (i.to_string(), rule.parse().unwrap()))
+        .collect();
+    let mut egraph = EGraph::default();
+    let root = egraph.add(&expr.parse().unwrap());
+    let saturation = egraph.saturate(&rules, theories, 30, 10_000).unwrap();
+    assert!(matches!(saturation, Saturation::Saturated(_)));
+    egraph.extract(root, cost).0.to_string()
+}
+
+#[test]
+fn saturation_keeps_every_alternative() {
+    // `normalize` cycles on the commutativity rule before ever reaching the
+    // unit rule.
+    let rules = ["X + Y = Y + X", "X + 0 = X"];
+    let theories = Theories::new();
+    assert_eq!(optimize(&rules, &theories, "0 + a", &NodeCount), "a");
+
+    // Reversible rules are used from right to left too.
+    let rules = ["double(X) <=> X + X"];
+    assert_eq!(
+        optimize(&rules, &theories, "b + b", &NodeCount),
+        "double(b)"
+    );
+    let rules = ["f(a, b, c) <=> g(h(a))"];
+    assert_eq!(
+        optimize(&rules, &theories, "f(a, b, c)", &NodeCount),
+        "g(h(a))"
+    );
+    assert_eq!(optimize(&rules, &theories, "g(h(a))", &Depth), "f(a, b, c)");
+
+    // Numbers fold and guards are checked against the classes.
+    let rules = ["X / X = 1 if X != 0", "X * 1 = X"];
+    assert_eq!(
+        optimize(&rules, &theories, "(2 * 3 + y) * (x / x)", &NodeCount),
+        "6 + y"
+    );
+    assert_eq!(optimize(&rules, &theories, "0 / 0", &NodeCount), "0 / 0");
+}
+
+#[test]
+fn saturation_with_theories() {
+    let mut theories = Theories::new();
+    theories.insert("add".to_string(), Theory::AC);
+    let rules = ["a + b = c"];
+    assert_eq!(
+        optimize(&rules, &theories, "b + (d + a)", &NodeCount),
+        "d + c"
+    );
+    let rules = ["f(X, Y) = X"];
+    theories.insert("f".to_string(), Theory::C);
+    assert_eq!(optimize(&rules, &theories, "f(g(a), b)", &NodeCount), "b");
+}
+
+#[test]
+fn saturation_stops_at_the_limits() {
+    let rules = vec![("grow".to_string(), "f(X) = f(g(X))".parse().unwrap())];
+    let mut egraph = EGraph::default();
+    let root = egraph.add(&"f(a)".parse().unwrap());
+    let saturation = egraph.saturate(&rules, &Theories::new(), 5, 1000).unwrap();
+    assert_eq!(saturation, Saturation::IterationLimit);
+    assert_eq!(egraph.extract(root, &NodeCount).0.to_string(), "f(a)");
+    let saturation = egraph.saturate(&rules, &Theories::new(), 100, 20).unwrap();
+    assert_eq!(saturation, Saturation::NodeLimit);
+}
+
+#[test]
+fn functor_variables_are_the_constants_they_name() {
+    let theories = Theories::new();
+    let rules = ["app(F, F(X)) = X"];
+    assert_eq!(optimize(&rules, &theories, "app(f, f(a))", &NodeCount), "a");
+    assert_eq!(
+        optimize(&rules, &theories, "app(g, f(a))", &NodeCount),
+        "app(g, f(a))"
+    );
+}
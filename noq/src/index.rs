@@ -0,0 +1,217 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    arena::{Arena, ExprId, NameId, Node},
+    is_var_name,
+    number::Number,
+    theory::Theories,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Key {
+    Sym(NameId),
+    Num(Number),
+    Fun(NameId, usize),
+    // A variable in a rule head, matching any subexpression.
+    Star,
+    // A functor variable with the given number of arguments, matching any
+    // functor with as many.
+    FunStar(usize),
+    // A functor with a theory, matching any application of it. Its arguments
+    // are not indexed since they can match in any order.
+    Theory(NameId),
+}
+
+#[derive(Debug, Default)]
+struct TreeNode {
+    children: HashMap<Key, TreeNode>,
+    // Rules whose heads end at this node.
+    rules: Vec<usize>,
+}
+
+/// Discrimination tree over the heads of a set of rules. Heads are flattened
+/// into their pre-order sequence of functors, so looking up an expression
+/// walks it once and only yields rules whose heads agree with it everywhere
+/// except under variables.
+///
+/// Candidates still have to be checked with `pattern_match`, the index does
+/// not know that the two `X` in `f(X, X)` have to be equal.
+#[derive(Debug, Default)]
+pub struct RuleIndex {
+    root: TreeNode,
+    rules_count: usize,
+    // Rules whose heads mention functors with theories or, if there are any
+    // theories, repeat a variable. Those have to be matched modulo them.
+    modulo: Vec<bool>,
+}
+
+/// How much work the rule index saved while normalizing.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct IndexStats {
+    /// Subexpressions looked up in the index.
+    pub lookups: usize,
+    /// Rules the index returned for them.
+    pub candidates: usize,
+    /// Candidates that actually matched.
+    pub matches: usize,
+    /// Rules that would have been tried without the index.
+    pub without_index: usize,
+}
+
+impl IndexStats {
+    /// Adds up the counts of two runs.
+    pub fn add(&mut self, other: &IndexStats) {
+        self.lookups += other.lookups;
+        self.candidates += other.candidates;
+        self.matches += other.matches;
+        self.without_index += other.without_index;
+    }
+}
+
+impl RuleIndex {
+    pub fn new(arena: &Arena, heads: &[ExprId], theories: &Theories) -> Self {
+        let mut index = Self::default();
+        for (rule, &head) in heads.iter().enumerate() {
+            let mut node = &mut index.root;
+            let keys = keys(arena, head, theories);
+            // A repeated variable compares its values, which are only equal
+            // modulo theories if compared that way.
+            let modulo = keys.iter().any(|key| matches!(key, Key::Theory(_)))
+                || (!theories.is_empty() && repeats_var(arena, head));
+            index.modulo.push(modulo);
+            for key in keys {
+                node = node.children.entry(key).or_default();
+            }
+            node.rules.push(rule);
+        }
+        index.rules_count = heads.len();
+        index
+    }
+
+    pub fn is_modulo(&self, rule: usize) -> bool {
+        self.modulo[rule]
+    }
+
+    /// Indices of the rules whose heads might match `expr`, in ascending order.
+    pub fn candidates(&self, arena: &Arena, expr: ExprId, stats: &mut IndexStats) -> Vec<usize> {
+        fn walk(node: &TreeNode, arena: &Arena, pending: &mut Vec<ExprId>, rules: &mut Vec<usize>) {
+            let Some(expr) = pending.pop() else {
+                rules.extend(&node.rules);
+                return;
+            };
+            if let Some(child) = node.children.get(&Key::Star) {
+                walk(child, arena, pending, rules);
+            }
+            let (key, args): (Key, &[ExprId]) = match arena.node(expr) {
+                Node::Sym(name) => (Key::Sym(*name), &[]),
+                Node::Num(number) => (Key::Num(*number), &[]),
+                Node::Fun(name, args) => (Key::Fun(*name, args.len()), args),
+                // Variables in the expression only match variables in heads.
+                Node::Var(_) => {
+                    pending.push(expr);
+                    return;
+                }
+            };
+            if let Key::Fun(name, 2) = key {
+                if let Some(child) = node.children.get(&Key::Theory(name)) {
+                    walk(child, arena, pending, rules);
+                }
+            }
+            let fun_star = match key {
+                Key::Fun(_, arity) => node.children.get(&Key::FunStar(arity)),
+                _ => None,
+            };
+            for child in [node.children.get(&key), fun_star].into_iter().flatten() {
+                let depth = pending.len();
+                pending.extend(args.iter().rev());
+                walk(child, arena, pending, rules);
+                pending.truncate(depth);
+            }
+            pending.push(expr);
+        }
+
+        let mut rules = Vec::new();
+        walk(&self.root, arena, &mut vec![expr], &mut rules);
+        rules.sort_unstable();
+        rules.dedup();
+        stats.lookups += 1;
+        stats.candidates += rules.len();
+        stats.without_index += self.rules_count;
+        rules
+    }
+}
+
+fn repeats_var(arena: &Arena, expr: ExprId) -> bool {
+    fn walk(arena: &Arena, expr: ExprId, seen: &mut HashSet<NameId>) -> bool {
+        match arena.node(expr) {
+            Node::Var(name) => !seen.insert(*name),
+            Node::Sym(_) | Node::Num(_) => false,
+            Node::Fun(name, args) => {
+                (is_var_name(arena.name(*name)) && !seen.insert(*name))
+                    || args.iter().any(|&arg| walk(arena, arg, seen))
+            }
+        }
+    }
+
+    walk(arena, expr, &mut HashSet::new())
+}
+
+fn keys(arena: &Arena, expr: ExprId, theories: &Theories) -> Vec<Key> {
+    fn keys_impl(arena: &Arena, expr: ExprId, theories: &Theories, keys: &mut Vec<Key>) {
+        match arena.node(expr) {
+            Node::Var(_) => keys.push(Key::Star),
+            Node::Sym(name) => keys.push(Key::Sym(*name)),
+            Node::Num(number) => keys.push(Key::Num(*number)),
+            Node::Fun(name, args)
+                if args.len() == 2 && theories.contains_key(arena.name(*name)) =>
+            {
+                keys.push(Key::Theory(*name))
+            }
+            Node::Fun(name, args) => {
+                if is_var_name(arena.name(*name)) {
+                    keys.push(Key::FunStar(args.len()));
+                } else {
+                    keys.push(Key::Fun(*name, args.len()));
+                }
+                for &arg in args {
+                    keys_impl(arena, arg, theories, keys);
+                }
+            }
+        }
+    }
+
+    let mut keys = Vec::new();
+    keys_impl(arena, expr, theories, &mut keys);
+    keys
+}
+
+#[test]
+fn index_filters_candidate_rules() {
+    let mut arena = Arena::default();
+    let heads: Vec<ExprId> = [
+        "add(zero, X)",
+        "add(succ(X), Y)",
+        "mul(zero, X)",
+        "mul(X, Y)",
+        "X",
+        "add(X, X)",
+        "F(zero, X)",
+    ]
+    .iter()
+    .map(|head| arena.intern(&head.parse().unwrap()))
+    .collect();
+    let index = RuleIndex::new(&arena, &heads, &Theories::new());
+    let mut stats = IndexStats::default();
+    let mut candidates = |expr: &str| {
+        let expr = arena.intern(&expr.parse().unwrap());
+        index.candidates(&arena, expr, &mut stats)
+    };
+    assert_eq!(candidates("add(zero, succ(zero))"), [0, 4, 5, 6]);
+    assert_eq!(candidates("add(succ(zero), zero)"), [1, 4, 5]);
+    assert_eq!(candidates("mul(zero, zero)"), [2, 3, 4, 6]);
+    assert_eq!(candidates("mul(a, b, c)"), [4]);
+    assert_eq!(candidates("zero"), [4]);
+    assert_eq!(stats.lookups, 5);
+    assert_eq!(stats.candidates, 13);
+    assert_eq!(stats.without_index, 35);
+}
@@ -0,0 +1,2015 @@
+//! A term rewriting library: expressions, rules matched modulo equational
+//! theories, and the strategies to apply them with. The `noq` binary is a
+//! REPL on top of it.
+
+#![warn(missing_docs)]
+
+use std::{collections::HashMap, fmt::Display, iter::Peekable, str::FromStr, sync::OnceLock};
+
+use arena::{Arena, IdRule, IdStep};
+use number::Number;
+use theory::{equal_modulo, Theories};
+
+mod arena;
+/// Knuth-Bendix completion of a set of rules.
+pub mod completion;
+/// The commands of the REPL and of scripts.
+pub mod context;
+/// Critical pairs and local confluence.
+pub mod critical;
+/// Equality saturation on e-graphs.
+pub mod egraph;
+mod index;
+/// Exact rational numbers and the built-in arithmetic on them.
+pub mod number;
+/// Term orders that prove rules terminate.
+pub mod order;
+/// Derivations exported as text, Markdown or JSON.
+pub mod proof;
+/// Prolog-style clauses and queries.
+pub mod query;
+/// Expressions drawn as trees and Graphviz graphs.
+pub mod render;
+/// Tactics that combine rules into bigger steps.
+pub mod tactic;
+/// Associative and commutative functors and matching modulo them.
+pub mod theory;
+/// Syntactic unification.
+pub mod unify;
+
+pub use index::IndexStats;
+pub use theory::pattern_match;
+
+/// An expression, the thing rules rewrite. Parse one with `str::parse` and
+/// print it back with `Display`, which writes functors of the default
+/// operators infix.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Expr {
+    /// Constant, only matches itself.
+    Sym(String),
+    /// Pattern variable, matches any expression.
+    Var(String),
+    /// Exact rational literal, only matches itself.
+    Num(Number),
+    /// Functor (self-referential type). A functor named like a variable matches
+    /// any functor with as many arguments and binds its name as a symbol.
+    Fun(String, Vec<Expr>),
+}
+
+impl Display for Expr {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::result::Result<(), std::fmt::Error> {
+        write!(f, "{}", default_operators().show(self))
+    }
+}
+
+/// Which way a chain of the same operator groups.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Assoc {
+    /// `a - b - c` is `(a - b) - c`.
+    Left,
+    /// `a ^ b ^ c` is `a ^ (b ^ c)`.
+    Right,
+}
+
+/// A binary infix operator is sugar for a functor with two arguments:
+/// `a + b` is parsed as `add(a, b)` and printed back the same way.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Operator {
+    /// How the operator is written, like `+`.
+    pub symbol: String,
+    /// The functor it stands for, like `add`.
+    pub name: String,
+    /// Operators with a higher precedence bind tighter.
+    pub precedence: usize,
+    /// How a chain of the operator groups.
+    pub assoc: Assoc,
+}
+
+/// The infix operators the parser and printer know about, `+ - * /` with
+/// the usual precedences by default.
+#[derive(Debug, Clone)]
+pub struct Operators(Vec<Operator>);
+
+impl Default for Operators {
+    fn default() -> Self {
+        let op = |symbol: &str, name: &str, precedence, assoc| Operator {
+            symbol: symbol.to_string(),
+            name: name.to_string(),
+            precedence,
+            assoc,
+        };
+        Self(vec![
+            op("+", "add", 1, Assoc::Left),
+            op("-", "sub", 1, Assoc::Left),
+            op("*", "mul", 2, Assoc::Left),
+            op("/", "div", 2, Assoc::Left),
+            op("^", "pow", 3, Assoc::Right),
+        ])
+    }
+}
+
+fn default_operators() -> &'static Operators {
+    static OPERATORS: OnceLock<Operators> = OnceLock::new();
+    OPERATORS.get_or_init(Operators::default)
+}
+
+impl Operators {
+    /// Replaces any operator with the same symbol or functor name.
+    pub fn define(&mut self, op: Operator) {
+        self.0
+            .retain(|other| other.symbol != op.symbol && other.name != op.name);
+        self.0.push(op);
+    }
+
+    /// The operator written as `symbol`.
+    pub fn by_symbol(&self, symbol: &str) -> Option<&Operator> {
+        self.0.iter().find(|op| op.symbol == symbol)
+    }
+
+    /// The operator for the functor `name`.
+    pub fn by_name(&self, name: &str) -> Option<&Operator> {
+        self.0.iter().find(|op| op.name == name)
+    }
+
+    // The operator an expression is printed with, if any.
+    fn of_expr(&self, expr: &Expr) -> Option<&Operator> {
+        match expr {
+            Expr::Fun(name, args) if args.len() == 2 => self.by_name(name),
+            _ => None,
+        }
+    }
+
+    /// Displays `expr` with these operators infix.
+    pub fn show<'a>(&'a self, expr: &'a Expr) -> ShowExpr<'a> {
+        ShowExpr {
+            expr,
+            operators: self,
+        }
+    }
+
+    /// Displays `rule` with these operators infix.
+    pub fn show_rule<'a>(&'a self, rule: &'a Rule) -> ShowRule<'a> {
+        ShowRule {
+            rule,
+            operators: self,
+        }
+    }
+}
+
+/// An expression displayed with a set of operators, see `Operators::show`.
+pub struct ShowExpr<'a> {
+    expr: &'a Expr,
+    operators: &'a Operators,
+}
+
+impl Display for ShowExpr<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::result::Result<(), std::fmt::Error> {
+        let show = |expr| self.operators.show(expr);
+        match self.expr {
+            Expr::Sym(name) | Expr::Var(name) => write!(f, "{}", name),
+            Expr::Num(number) => write!(f, "{}", number),
+            Expr::Fun(name, args) => {
+                if let Some(op) = self.operators.of_expr(self.expr) {
+                    // Operands only need parens if they bind looser than `op`,
+                    // or just as tight but on the side `op` does not associate
+                    // towards.
+                    let needs_parens = |arg, assoc| match self.operators.of_expr(arg) {
+                        Some(arg_op) => {
+                            arg_op.precedence < op.precedence
+                                || (arg_op.precedence == op.precedence && op.assoc != assoc)
+                        }
+                        None => false,
+                    };
+                    for (index, (arg, assoc)) in
+                        args.iter().zip([Assoc::Left, Assoc::Right]).enumerate()
+                    {
+                        if index > 0 {
+                            write!(f, " {} ", op.symbol)?;
+                        }
+                        if needs_parens(arg, assoc) {
+                            write!(f, "({})", show(arg))?;
+                        } else {
+                            write!(f, "{}", show(arg))?;
+                        }
+                    }
+                    return Ok(());
+                }
+                write!(f, "{}(", name)?;
+                for (index, arg) in args.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", show(arg))?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+/// A rewrite rule `head = body`: an expression matching `head` is replaced
+/// by `body` with the variables bound by the match substituted.
+#[derive(Debug, Clone)]
+pub struct Rule {
+    /// The pattern the rule matches.
+    pub head: Expr,
+    /// What a match is replaced with.
+    pub body: Expr,
+    /// Conditions on the bindings of the head that have to hold for the rule
+    /// to apply.
+    pub guards: Vec<Guard>,
+    /// Written with `<=>`: saturation also rewrites the body to the head.
+    /// Everything else only uses the rule from left to right.
+    pub reversible: bool,
+}
+
+/// A condition written after `if` in a rule.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Guard {
+    /// The expressions are equal, `a == b`.
+    Equal(Expr, Expr),
+    /// The expressions differ, `a != b`.
+    NotEqual(Expr, Expr),
+    /// The expression is a number literal.
+    Number(Expr),
+}
+
+impl Guard {
+    /// Whether the guard holds once the variables of the head are replaced by
+    /// `bindings`, comparing expressions modulo `theories`.
+    pub fn holds(&self, bindings: &Bindings, theories: &Theories) -> Result<bool, NoqError> {
+        let substitute = |expr| substitute_bindings(bindings, expr);
+        Ok(match self {
+            Guard::Equal(a, b) => equal_modulo(&substitute(a)?, &substitute(b)?, theories),
+            Guard::NotEqual(a, b) => !equal_modulo(&substitute(a)?, &substitute(b)?, theories),
+            Guard::Number(a) => matches!(substitute(a)?, Expr::Num(_)),
+        })
+    }
+}
+
+/// A rule displayed with a set of operators, see `Operators::show_rule`.
+pub struct ShowRule<'a> {
+    rule: &'a Rule,
+    operators: &'a Operators,
+}
+
+impl Display for ShowRule<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::result::Result<(), std::fmt::Error> {
+        let show = |expr| self.operators.show(expr);
+        let equals = if self.rule.reversible { "<=>" } else { "=" };
+        write!(
+            f,
+            "{} {} {}",
+            show(&self.rule.head),
+            equals,
+            show(&self.rule.body)
+        )?;
+        for (index, guard) in self.rule.guards.iter().enumerate() {
+            write!(f, "{}", if index == 0 { " if " } else { ", " })?;
+            match guard {
+                Guard::Equal(a, b) => write!(f, "{} == {}", show(a), show(b))?,
+                Guard::NotEqual(a, b) => write!(f, "{} != {}", show(a), show(b))?,
+                Guard::Number(a) => write!(f, "number({})", show(a))?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Display for Rule {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::result::Result<(), std::fmt::Error> {
+        write!(f, "{}", default_operators().show_rule(self))
+    }
+}
+
+/// Replaces the variables of `expr` by their values in `bindings`, leaving
+/// unbound ones alone.
+pub fn substitute_bindings(bindings: &Bindings, expr: &Expr) -> Result<Expr, NoqError> {
+    use Expr::*;
+    match expr {
+        Sym(_) | Num(_) => Ok(expr.clone()),
+        Var(name) => {
+            if let Some(value) = bindings.get(name) {
+                Ok(value.clone())
+            } else {
+                Ok(expr.clone())
+            }
+        }
+        Fun(name, args) => {
+            let new_name = match bindings.get(name) {
+                Some(Sym(new_name) | Var(new_name)) => new_name.clone(),
+                None => name.clone(),
+                Some(value) => {
+                    return Err(NoqError::FunctorNotSymbol {
+                        loc: None,
+                        name: name.clone(),
+                        value: value.clone(),
+                    })
+                }
+            };
+            let mut new_args = Vec::new();
+            for arg in args {
+                new_args.push(substitute_bindings(bindings, arg)?);
+            }
+            Ok(Fun(new_name, new_args))
+        }
+    }
+}
+
+/// Argument indices leading from the root of an expression to one of its
+/// subexpressions.
+pub type Path = Vec<usize>;
+
+impl Expr {
+    /// The subexpression at `path`, None if there is none.
+    pub fn at(&self, path: &[usize]) -> Option<&Expr> {
+        match (path.split_first(), self) {
+            (None, _) => Some(self),
+            (Some((&i, rest)), Expr::Fun(_, args)) => args.get(i)?.at(rest),
+            (Some(_), _) => None,
+        }
+    }
+
+    /// A copy with the subexpression at `path` replaced, None if there is no
+    /// such subexpression.
+    pub fn replace_at(&self, path: &[usize], new_expr: Expr) -> Option<Expr> {
+        match (path.split_first(), self) {
+            (None, _) => Some(new_expr),
+            (Some((&i, rest)), Expr::Fun(name, args)) if i < args.len() => {
+                let mut new_args = args.clone();
+                new_args[i] = args[i].replace_at(rest, new_expr)?;
+                Some(Expr::Fun(name.clone(), new_args))
+            }
+            (Some(_), _) => None,
+        }
+    }
+
+    /// Names of the variables, functor variables included, in order of
+    /// appearance.
+    pub fn vars(&self) -> Vec<String> {
+        fn vars_impl(expr: &Expr, vars: &mut Vec<String>) {
+            match expr {
+                Expr::Var(name) if !vars.contains(name) => vars.push(name.clone()),
+                Expr::Var(_) | Expr::Sym(_) | Expr::Num(_) => {}
+                Expr::Fun(name, args) => {
+                    if is_var_name(name) && !vars.contains(name) {
+                        vars.push(name.clone());
+                    }
+                    for arg in args {
+                        vars_impl(arg, vars);
+                    }
+                }
+            }
+        }
+
+        let mut vars = Vec::new();
+        vars_impl(self, &mut vars);
+        vars
+    }
+
+    /// Paths to every subexpression, parents before children.
+    pub fn paths(&self) -> Vec<Path> {
+        fn paths_impl(expr: &Expr, path: &mut Path, paths: &mut Vec<Path>) {
+            paths.push(path.to_vec());
+            if let Expr::Fun(_, args) = expr {
+                for (i, arg) in args.iter().enumerate() {
+                    path.push(i);
+                    paths_impl(arg, path, paths);
+                    path.pop();
+                }
+            }
+        }
+
+        let mut paths = Vec::new();
+        paths_impl(self, &mut Vec::new(), &mut paths);
+        paths
+    }
+}
+
+/// Which of the matches of a rule in an expression get rewritten.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Strategy {
+    /// Only the first match in pre-order.
+    First,
+    /// Every match, parents before children. The result of a rewrite is not
+    /// visited again.
+    TopDown,
+    /// Every match, children before parents.
+    BottomUp,
+    /// Only the subexpression at the given path of argument indices.
+    At(Path),
+    /// Only the n-th match, counting from 1 in the order of `match_paths`.
+    Match(usize),
+}
+
+impl Rule {
+    /// Rewrites every match top-down, returning `expr` unchanged if there is
+    /// none.
+    pub fn apply_all(&self, expr: &Expr) -> Result<Expr, NoqError> {
+        Ok(self
+            .apply(expr, &Strategy::TopDown)?
+            .unwrap_or_else(|| expr.clone()))
+    }
+
+    /// Returns None if the rule did not match anywhere. Matches syntactically,
+    /// without any theories.
+    pub fn apply(&self, expr: &Expr, strategy: &Strategy) -> Result<Option<Expr>, NoqError> {
+        Ok(self
+            .apply_with_paths(&Theories::new(), expr, strategy)?
+            .map(|(new_expr, _)| new_expr))
+    }
+
+    /// Same as `apply` but modulo `theories`, also returning the paths of the
+    /// subexpressions that were rewritten, in the order they were rewritten.
+    /// Where there are several matches the first one is used.
+    pub fn apply_with_paths(
+        &self,
+        theories: &Theories,
+        expr: &Expr,
+        strategy: &Strategy,
+    ) -> Result<Option<(Expr, Vec<Path>)>, NoqError> {
+        let mut paths = Vec::new();
+        let new_expr = match strategy {
+            Strategy::First => self.apply_first(theories, expr, &mut Vec::new(), &mut paths)?,
+            Strategy::TopDown => {
+                self.apply_top_down(theories, expr, &mut Vec::new(), &mut paths)?
+            }
+            Strategy::BottomUp => {
+                self.apply_bottom_up(theories, expr, &mut Vec::new(), &mut paths)?
+            }
+            Strategy::At(path) => self.apply_at(theories, expr, path, &mut paths)?,
+            Strategy::Match(n) => {
+                let match_paths = self.match_paths(theories, expr)?;
+                match n.checked_sub(1).and_then(|i| match_paths.get(i)) {
+                    Some(path) => self.apply_at(theories, expr, path, &mut paths)?,
+                    None if match_paths.is_empty() => None,
+                    None => {
+                        return Err(NoqError::NoSuchMatch {
+                            loc: None,
+                            n: *n,
+                            count: match_paths.len(),
+                        })
+                    }
+                }
+            }
+        };
+        Ok(new_expr.map(|new_expr| (new_expr, paths)))
+    }
+
+    /// Paths to every subexpression the rule rewrites modulo `theories`,
+    /// parents before children and left to right.
+    pub fn match_paths(&self, theories: &Theories, expr: &Expr) -> Result<Vec<Path>, NoqError> {
+        let mut match_paths = Vec::new();
+        for path in expr.paths() {
+            let subexpr = expr.at(&path).expect("paths of expr are valid");
+            if theory::rewrite(self, subexpr, theories)?.is_some() {
+                match_paths.push(path);
+            }
+        }
+        Ok(match_paths)
+    }
+
+    fn apply_root(
+        &self,
+        theories: &Theories,
+        expr: &Expr,
+        path: &[usize],
+        paths: &mut Vec<Path>,
+    ) -> Result<Option<Expr>, NoqError> {
+        let new_expr = theory::rewrite(self, expr, theories)?;
+        if new_expr.is_some() {
+            paths.push(path.to_vec());
+        }
+        Ok(new_expr)
+    }
+
+    fn apply_first(
+        &self,
+        theories: &Theories,
+        expr: &Expr,
+        path: &mut Path,
+        paths: &mut Vec<Path>,
+    ) -> Result<Option<Expr>, NoqError> {
+        if let Some(new_expr) = self.apply_root(theories, expr, path, paths)? {
+            return Ok(Some(new_expr));
+        }
+        if let Expr::Fun(name, args) = expr {
+            for (i, arg) in args.iter().enumerate() {
+                path.push(i);
+                let new_arg = self.apply_first(theories, arg, path, paths)?;
+                path.pop();
+                if let Some(new_arg) = new_arg {
+                    let mut new_args = args.clone();
+                    new_args[i] = new_arg;
+                    return Ok(Some(Expr::Fun(name.clone(), new_args)));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn apply_top_down(
+        &self,
+        theories: &Theories,
+        expr: &Expr,
+        path: &mut Path,
+        paths: &mut Vec<Path>,
+    ) -> Result<Option<Expr>, NoqError> {
+        if let Some(new_expr) = self.apply_root(theories, expr, path, paths)? {
+            return Ok(Some(new_expr));
+        }
+        match expr {
+            Expr::Sym(_) | Expr::Var(_) | Expr::Num(_) => Ok(None),
+            Expr::Fun(name, args) => rebuild_fun(name, args, path, |arg, path| {
+                self.apply_top_down(theories, arg, path, paths)
+            }),
+        }
+    }
+
+    fn apply_bottom_up(
+        &self,
+        theories: &Theories,
+        expr: &Expr,
+        path: &mut Path,
+        paths: &mut Vec<Path>,
+    ) -> Result<Option<Expr>, NoqError> {
+        let new_expr = match expr {
+            Expr::Sym(_) | Expr::Var(_) | Expr::Num(_) => None,
+            Expr::Fun(name, args) => rebuild_fun(name, args, path, |arg, path| {
+                self.apply_bottom_up(theories, arg, path, paths)
+            })?,
+        };
+        match new_expr {
+            Some(new_expr) => Ok(Some(
+                self.apply_root(theories, &new_expr, path, paths)?
+                    .unwrap_or(new_expr),
+            )),
+            None => self.apply_root(theories, expr, path, paths),
+        }
+    }
+
+    fn apply_at(
+        &self,
+        theories: &Theories,
+        expr: &Expr,
+        path: &[usize],
+        paths: &mut Vec<Path>,
+    ) -> Result<Option<Expr>, NoqError> {
+        let Some(subexpr) = expr.at(path) else {
+            return Ok(None);
+        };
+        Ok(self
+            .apply_root(theories, subexpr, path, paths)?
+            .and_then(|new_subexpr| expr.replace_at(path, new_subexpr)))
+    }
+}
+
+// Rebuilds a functor by rewriting each argument with `f`, returning None if
+// none of the arguments changed. `f` gets the path to the argument.
+fn rebuild_fun(
+    name: &str,
+    args: &[Expr],
+    path: &mut Path,
+    mut f: impl FnMut(&Expr, &mut Path) -> Result<Option<Expr>, NoqError>,
+) -> Result<Option<Expr>, NoqError> {
+    let mut changed = false;
+    let mut new_args = Vec::new();
+    for (i, arg) in args.iter().enumerate() {
+        path.push(i);
+        let new_arg = f(arg, path);
+        path.pop();
+        match new_arg? {
+            Some(new_arg) => {
+                changed = true;
+                new_args.push(new_arg);
+            }
+            None => new_args.push(arg.clone()),
+        }
+    }
+    if changed {
+        Ok(Some(Expr::Fun(name.to_string(), new_args)))
+    } else {
+        Ok(None)
+    }
+}
+
+#[test]
+fn apply_strategies() {
+    let rule: Rule = "f(X) = g(X)".parse().unwrap();
+    let apply = |expr: &str, strategy: Strategy| {
+        rule.apply(&expr.parse().unwrap(), &strategy)
+            .unwrap()
+            .map(|expr| expr.to_string())
+    };
+    assert_eq!(
+        apply("h(f(a), f(b))", Strategy::First).unwrap(),
+        "h(g(a), f(b))"
+    );
+    assert_eq!(
+        apply("h(f(a), f(b))", Strategy::TopDown).unwrap(),
+        "h(g(a), g(b))"
+    );
+    assert_eq!(apply("f(f(a))", Strategy::TopDown).unwrap(), "g(f(a))");
+    assert_eq!(apply("f(f(a))", Strategy::BottomUp).unwrap(), "g(g(a))");
+    assert_eq!(
+        apply("h(f(a), f(b))", Strategy::At(vec![1])).unwrap(),
+        "h(f(a), g(b))"
+    );
+    assert_eq!(apply("h(f(a), f(b))", Strategy::At(vec![])), None);
+    assert_eq!(apply("h(f(a), f(b))", Strategy::At(vec![2])), None);
+    assert_eq!(apply("h(a, b)", Strategy::TopDown), None);
+
+    let paths = |expr: &str, strategy: Strategy| {
+        rule.apply_with_paths(&Theories::new(), &expr.parse().unwrap(), &strategy)
+            .unwrap()
+            .map(|(_, paths)| paths)
+    };
+    assert_eq!(
+        paths("h(f(a), k(f(b)))", Strategy::First).unwrap(),
+        [vec![0]]
+    );
+    assert_eq!(
+        paths("h(f(a), k(f(b)))", Strategy::TopDown).unwrap(),
+        [vec![0], vec![1, 0]]
+    );
+    assert_eq!(
+        paths("f(f(a))", Strategy::BottomUp).unwrap(),
+        [vec![0], vec![]]
+    );
+    assert_eq!(
+        paths("h(f(a), f(b))", Strategy::At(vec![1])).unwrap(),
+        [vec![1]]
+    );
+}
+
+#[test]
+fn numbered_matches() {
+    let rule: Rule = "f(X) = g(X)".parse().unwrap();
+    let expr: Expr = "h(f(f(a)), k(f(b)))".parse().unwrap();
+    assert_eq!(
+        rule.match_paths(&Theories::new(), &expr).unwrap(),
+        [vec![0], vec![0, 0], vec![1, 0]]
+    );
+    let apply = |n| {
+        rule.apply(&expr, &Strategy::Match(n))
+            .map(|expr| expr.unwrap().to_string())
+            .map_err(|error| error.to_string())
+    };
+    assert_eq!(apply(2).unwrap(), "h(f(g(a)), k(f(b)))");
+    assert_eq!(apply(3).unwrap(), "h(f(f(a)), k(g(b)))");
+    assert_eq!(
+        apply(0).unwrap_err(),
+        "no match number 0, the matches are numbered 1 to 3"
+    );
+    assert_eq!(
+        apply(4).unwrap_err(),
+        "no match number 4, the matches are numbered 1 to 3"
+    );
+    // Without any match it is the rule that does not match.
+    assert_eq!(
+        rule.apply(&"a".parse().unwrap(), &Strategy::Match(1)),
+        Ok(None)
+    );
+
+    let strategy = |source: &str| parse_strategy(&mut Lexer::new(source.chars())).unwrap();
+    assert_eq!(strategy("2"), Strategy::Match(2));
+    assert_eq!(strategy("at 1 0"), Strategy::At(vec![1, 0]));
+}
+
+#[test]
+fn constants_only_match_themselves() {
+    let rule: Rule = "add(zero, X) = X".parse().unwrap();
+    let apply = |expr: &str| rule.apply_all(&expr.parse().unwrap()).unwrap().to_string();
+    assert_eq!(apply("add(zero, succ(zero))"), "succ(zero)");
+    assert_eq!(apply("add(one, succ(zero))"), "one + succ(zero)");
+    assert_eq!(apply("add(zero, add(zero, _x))"), "zero + _x");
+
+    let rule: Rule = "eq(X, X) = true".parse().unwrap();
+    assert_eq!(
+        rule.apply_all(&"eq(a, a)".parse().unwrap())
+            .unwrap()
+            .to_string(),
+        "true"
+    );
+    assert_eq!(
+        rule.apply_all(&"eq(a, b)".parse().unwrap())
+            .unwrap()
+            .to_string(),
+        "eq(a, b)"
+    );
+}
+
+#[test]
+fn apply_all_matches_head_against_subexpressions() {
+    let swap: Rule = "swap(pair(A, B)) = pair(B, A)".parse().unwrap();
+    let expr: Expr = "foo(swap(pair(f(a), g(b))))".parse().unwrap();
+    assert_eq!(
+        swap.apply_all(&expr).unwrap().to_string(),
+        "foo(pair(g(b), f(a)))"
+    );
+}
+
+#[test]
+fn guards_are_checked_after_matching() {
+    let rule: Rule = "div(X, X) = 1 if X != 0".parse().unwrap();
+    let apply = |rule: &Rule, expr: &str| {
+        rule.apply(&expr.parse().unwrap(), &Strategy::First)
+            .unwrap()
+            .map(|expr| expr.to_string())
+    };
+    assert_eq!(apply(&rule, "f(a / a)").unwrap(), "f(1)");
+    assert_eq!(apply(&rule, "f(0 / 0)"), None);
+    let rule: Rule = "double(X) = X + X if number(X)".parse().unwrap();
+    assert_eq!(apply(&rule, "double(2)").unwrap(), "2 + 2");
+    assert_eq!(apply(&rule, "double(a)"), None);
+
+    // A later match can satisfy the guards when the first one does not.
+    let mut theories = Theories::new();
+    theories.insert("add".to_string(), theory::Theory::AC);
+    let rule: Rule = "X + Y = Y if number(X)".parse().unwrap();
+    let (expr, _) = rule
+        .apply_with_paths(&theories, &"a + 1".parse().unwrap(), &Strategy::First)
+        .unwrap()
+        .unwrap();
+    assert_eq!(expr.to_string(), "a");
+
+    // Normalization goes through the arena.
+    let rules = vec![
+        (
+            "cancel".to_string(),
+            "div(X, X) = 1 if X != 0".parse().unwrap(),
+        ),
+        ("id".to_string(), "mul(1, X) = X".parse().unwrap()),
+    ];
+    let expr = "(a / a) * (0 / 0)".parse().unwrap();
+    assert_eq!(
+        normal_form(&rules, &expr, 10).unwrap(),
+        Ok("0 / 0".parse().unwrap())
+    );
+}
+
+#[test]
+fn functor_variables_match_any_functor() {
+    let apply = |rule: &str, expr: &str| {
+        let rule: Rule = rule.parse().unwrap();
+        rule.apply(&expr.parse().unwrap(), &Strategy::First)
+            .unwrap()
+            .map(|expr| expr.to_string())
+    };
+    assert_eq!(apply("F(X, X) = X", "f(a, a)").unwrap(), "a");
+    assert_eq!(apply("F(X, X) = X", "b * b").unwrap(), "b");
+    assert_eq!(apply("F(X, X) = X", "f(a, b)"), None);
+    assert_eq!(apply("F(X, X) = X", "f(a)"), None);
+    assert_eq!(apply("F(X) = X", "a"), None);
+    // Every occurrence of a functor variable has to be the same functor,
+    // including where it is used as an ordinary variable.
+    let distribute = "pair(F(X), F(Y)) = F(pair(X, Y))";
+    assert_eq!(
+        apply(distribute, "pair(f(a), f(b))").unwrap(),
+        "f(pair(a, b))"
+    );
+    assert_eq!(apply(distribute, "pair(f(a), g(b))"), None);
+    assert_eq!(apply("app(F, F(X)) = X", "app(f, f(a))").unwrap(), "a");
+    assert_eq!(apply("app(F, F(X)) = X", "app(g, f(a))"), None);
+    assert_eq!(apply("F(X, Y) = F(Y, X)", "a + b").unwrap(), "b + a");
+
+    // Normalization goes through the arena and the rule index.
+    let rules = vec![("idem".to_string(), "F(X, X) = X".parse().unwrap())];
+    let expr = "f(g(a + a, a), g(a, a))".parse().unwrap();
+    assert_eq!(
+        normal_form(&rules, &expr, 10).unwrap(),
+        Ok("a".parse().unwrap())
+    );
+}
+
+#[test]
+fn functor_variables_bound_to_non_symbols_are_errors() {
+    let rule: Rule = "wrap(F) = F(a)".parse().unwrap();
+    assert_eq!(
+        rule.apply_all(&"wrap(g)".parse().unwrap())
+            .unwrap()
+            .to_string(),
+        "g(a)"
+    );
+    let error = rule.apply_all(&"wrap(g(b))".parse().unwrap()).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "functor variable `F` is bound to g(b), which is not a symbol"
+    );
+}
+
+/// Values of the variables of a pattern after a successful match.
+pub type Bindings = HashMap<String, Expr>;
+
+/// A position in the source text, both counting from 1.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Loc {
+    /// The line.
+    pub row: usize,
+    /// The character within the line.
+    pub col: usize,
+}
+
+impl Display for Loc {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::result::Result<(), std::fmt::Error> {
+        write!(f, "{}:{}", self.row, self.col)
+    }
+}
+
+/// What a token is, with its value for the kinds that have one.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenKind {
+    /// A name, either a constant, a variable or a functor.
+    Sym(String),
+    /// A number literal, the numerator and denominator as written. A minus
+    /// in front of it is a separate token.
+    Num(u64, u64),
+    /// `(`
+    OpenParen,
+    /// `)`
+    CloseParen,
+    /// `,`
+    Comma,
+    /// `=`
+    Equals,
+    /// A run of operator characters, like `+` or `<=>`.
+    Op(String),
+    /// Any character the lexer does not know about, reported by the parser.
+    Invalid,
+}
+
+impl Display for TokenKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::result::Result<(), std::fmt::Error> {
+        match self {
+            TokenKind::Sym(name) => write!(f, "symbol `{}`", name),
+            TokenKind::Num(num, 1) => write!(f, "number `{}`", num),
+            TokenKind::Num(num, den) => write!(f, "number `{}/{}`", num, den),
+            TokenKind::OpenParen => write!(f, "open paren"),
+            TokenKind::CloseParen => write!(f, "close paren"),
+            TokenKind::Comma => write!(f, "comma"),
+            TokenKind::Equals => write!(f, "equals"),
+            TokenKind::Op(symbol) => write!(f, "operator `{}`", symbol),
+            TokenKind::Invalid => write!(f, "invalid token"),
+        }
+    }
+}
+
+/// A token and where it was found.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+    /// What the token is.
+    pub kind: TokenKind,
+    /// The source text of the token.
+    pub text: String,
+    /// Where the token starts.
+    pub loc: Loc,
+}
+
+/// Splits source text into tokens, tracking the location of each. Commands
+/// of the REPL are read from the same lexer as the expressions in them.
+pub struct Lexer<Chars: Iterator<Item = char>> {
+    chars: Peekable<Chars>,
+    peeked: Option<Token>,
+    // An operator lexed along with the number before it, see `lex_number`,
+    // or the rest of an operator split by `split_op`.
+    queued: Option<Token>,
+    row: usize,
+    col: usize,
+}
+
+impl<Chars: Iterator<Item = char>> Lexer<Chars> {
+    /// A lexer at the start of `chars`, on line 1.
+    pub fn new(chars: Chars) -> Self {
+        Self {
+            chars: chars.peekable(),
+            peeked: None,
+            queued: None,
+            row: 1,
+            col: 1,
+        }
+    }
+
+    /// Location of the next token, or of the end of input if there are no
+    /// tokens left.
+    pub fn loc(&mut self) -> Loc {
+        match self.peek() {
+            Some(token) => token.loc,
+            None => Loc {
+                row: self.row,
+                col: self.col,
+            },
+        }
+    }
+
+    /// The next token without consuming it.
+    pub fn peek(&mut self) -> Option<&Token> {
+        if self.peeked.is_none() {
+            self.peeked = self.next_token();
+        }
+        self.peeked.as_ref()
+    }
+
+    /// Consumes the next token only if it is a symbol that `known` maps to
+    /// something, so that an optional word at the end of a command leaves the
+    /// next command alone.
+    pub fn next_sym_if<T>(&mut self, known: impl FnOnce(&str) -> Option<T>) -> Option<T> {
+        let value = match self.peek() {
+            Some(Token {
+                kind: TokenKind::Sym(name),
+                ..
+            }) => known(name)?,
+            _ => return None,
+        };
+        self.next();
+        Some(value)
+    }
+
+    // Operators are lexed greedily, which glues a minus to the operator
+    // before it, as in `=-1` or `a*-1`. Unless `known` accepts the peeked
+    // operator as it is, it is split before its first minus after the start,
+    // so the minus can negate the number that follows.
+    fn split_op(&mut self, known: impl Fn(&str) -> bool) {
+        let Some(Token {
+            kind: TokenKind::Op(symbol),
+            loc,
+            ..
+        }) = self.peek().cloned()
+        else {
+            return;
+        };
+        if known(&symbol) {
+            return;
+        }
+        let Some(at) = symbol
+            .char_indices()
+            .skip(1)
+            .find(|&(_, x)| x == '-')
+            .map(|(i, _)| i)
+        else {
+            return;
+        };
+        let rest = symbol[at..].to_string();
+        let symbol = symbol[..at].to_string();
+        self.queued = Some(Token {
+            kind: op_kind(&rest),
+            text: rest,
+            loc: Loc {
+                row: loc.row,
+                col: loc.col + at,
+            },
+        });
+        self.peeked = Some(Token {
+            kind: op_kind(&symbol),
+            text: symbol,
+            loc,
+        });
+    }
+
+    fn next_char(&mut self) -> Option<char> {
+        let x = self.chars.next()?;
+        if x == '\n' {
+            self.row += 1;
+            self.col = 1;
+        } else {
+            self.col += 1;
+        }
+        Some(x)
+    }
+
+    fn next_token(&mut self) -> Option<Token> {
+        if let Some(token) = self.queued.take() {
+            return Some(token);
+        }
+        loop {
+            match self.chars.peek() {
+                Some(x) if x.is_whitespace() => {
+                    self.next_char();
+                }
+                // Comments run until the end of the line.
+                Some('#') => while self.next_char().is_some_and(|x| x != '\n') {},
+                _ => break,
+            }
+        }
+        let loc = Loc {
+            row: self.row,
+            col: self.col,
+        };
+        let x = self.next_char()?;
+        let mut text = x.to_string();
+        let kind = match x {
+            '(' => TokenKind::OpenParen,
+            ')' => TokenKind::CloseParen,
+            ',' => TokenKind::Comma,
+            _ if x.is_ascii_digit() => return Some(self.lex_number(loc, text)),
+            _ if is_op_char(x) => self.lex_op(&mut text),
+            _ if is_sym_char(x) => {
+                while let Some(&x) = self.chars.peek() {
+                    if !is_sym_char(x) {
+                        break;
+                    }
+                    text.push(x);
+                    self.next_char();
+                }
+                TokenKind::Sym(text.clone())
+            }
+            _ => TokenKind::Invalid,
+        };
+        Some(Token { kind, text, loc })
+    }
+
+    fn lex_op(&mut self, text: &mut String) -> TokenKind {
+        while let Some(&x) = self.chars.peek() {
+            if !is_op_char(x) {
+                break;
+            }
+            text.push(x);
+            self.next_char();
+        }
+        op_kind(text)
+    }
+
+    fn lex_digits(&mut self, text: &mut String) {
+        while let Some(&x) = self.chars.peek() {
+            if !x.is_ascii_digit() {
+                break;
+            }
+            text.push(x);
+            self.next_char();
+        }
+    }
+
+    // number := digits ['/' digits]
+    //
+    // A rational literal has no spaces around the slash, `1 / 2` is a
+    // division. A number that does not fit in 64 bits without its sign or
+    // has a zero denominator is an invalid token.
+    fn lex_number(&mut self, loc: Loc, mut text: String) -> Token {
+        self.lex_digits(&mut text);
+        let num = text.parse::<u64>().ok();
+        let mut number = num.map(|num| (num, 1));
+        if self.chars.peek() == Some(&'/') {
+            let op_loc = Loc {
+                row: self.row,
+                col: self.col,
+            };
+            self.next_char();
+            if self.chars.peek().is_some_and(|x| x.is_ascii_digit()) {
+                let mut den = String::new();
+                self.lex_digits(&mut den);
+                number = num.zip(den.parse().ok().filter(|&den| den != 0));
+                text = format!("{}/{}", text, den);
+            } else {
+                // Only one character of lookahead, so the operator that
+                // starts with the slash comes out as the next token.
+                let mut op = "/".to_string();
+                let kind = self.lex_op(&mut op);
+                self.queued = Some(Token {
+                    kind,
+                    text: op,
+                    loc: op_loc,
+                });
+            }
+        }
+        // Digits run straight into letters, as in `2x`.
+        while let Some(&x) = self.chars.peek().filter(|_| self.queued.is_none()) {
+            if !is_sym_char(x) {
+                break;
+            }
+            text.push(x);
+            self.next_char();
+            number = None;
+        }
+        let kind = match number {
+            Some((num, den)) => TokenKind::Num(num, den),
+            None => TokenKind::Invalid,
+        };
+        Token { kind, text, loc }
+    }
+}
+
+fn op_kind(text: &str) -> TokenKind {
+    if text == "=" {
+        TokenKind::Equals
+    } else {
+        TokenKind::Op(text.to_string())
+    }
+}
+
+fn is_sym_char(x: char) -> bool {
+    x.is_alphanumeric() || x == '_'
+}
+
+fn is_op_char(x: char) -> bool {
+    "+-*/^<>!=&|~%".contains(x)
+}
+
+/// Same convention as Prolog: names starting with an uppercase letter or an
+/// underscore are variables, everything else is a constant.
+pub fn is_var_name(name: &str) -> bool {
+    name.starts_with(|x: char| x.is_uppercase() || x == '_')
+}
+
+impl<Chars: Iterator<Item = char>> Iterator for Lexer<Chars> {
+    type Item = Token;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.peeked.take().or_else(|| self.next_token())
+    }
+}
+
+/// Everything that can go wrong in noq. Errors in the source text point at the
+/// offending token, errors found while rewriting get the location of the
+/// command that ran into them.
+#[derive(Debug, Clone, PartialEq)]
+pub enum NoqError {
+    /// A character that does not start any token.
+    InvalidToken {
+        /// Where the character is.
+        loc: Loc,
+        /// The character.
+        text: String,
+    },
+    /// A token, or the end of input, where the grammar wants something else.
+    UnexpectedToken {
+        /// Where the token is.
+        loc: Loc,
+        /// What the parser wanted there.
+        expected: String,
+        /// What it got instead.
+        found: String,
+    },
+    /// An operator that is not defined.
+    UnknownOperator {
+        /// Where the operator is.
+        loc: Loc,
+        /// How the operator is written.
+        symbol: String,
+    },
+    /// A rule that was asked to rewrite an expression does not match it.
+    NoMatch {
+        /// The command that applied the rule.
+        loc: Loc,
+        /// The name of the rule.
+        rule: String,
+        /// The expression it did not match.
+        expr: Expr,
+    },
+    /// A variable in the place of a functor name is bound to something other
+    /// than a symbol, so substituting it would not give an expression.
+    FunctorNotSymbol {
+        /// The command that ran into it, once known.
+        loc: Option<Loc>,
+        /// The variable.
+        name: String,
+        /// What it is bound to.
+        value: Expr,
+    },
+    /// A repeated tactic still changed the expression after this many
+    /// rounds.
+    RepeatLimit {
+        /// The command that ran the tactic, once known.
+        loc: Option<Loc>,
+        /// The number of rounds.
+        limit: usize,
+    },
+    /// `Strategy::Match` asked for a match the rule does not have.
+    NoSuchMatch {
+        /// The command that applied the rule, once known.
+        loc: Option<Loc>,
+        /// The match asked for, counting from 1.
+        n: usize,
+        /// How many matches there are.
+        count: usize,
+    },
+    /// Any other command that could not be carried out.
+    Command {
+        /// The command.
+        loc: Loc,
+        /// What went wrong.
+        message: String,
+    },
+}
+
+impl NoqError {
+    /// Gives an error that was found away from the source text the location
+    /// of the command that ran into it.
+    pub fn at(self, loc: Loc) -> Self {
+        match self {
+            NoqError::FunctorNotSymbol {
+                loc: None,
+                name,
+                value,
+            } => NoqError::FunctorNotSymbol {
+                loc: Some(loc),
+                name,
+                value,
+            },
+            NoqError::RepeatLimit { loc: None, limit } => NoqError::RepeatLimit {
+                loc: Some(loc),
+                limit,
+            },
+            NoqError::NoSuchMatch {
+                loc: None,
+                n,
+                count,
+            } => NoqError::NoSuchMatch {
+                loc: Some(loc),
+                n,
+                count,
+            },
+            error => error,
+        }
+    }
+}
+
+impl Display for NoqError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::result::Result<(), std::fmt::Error> {
+        match self {
+            NoqError::InvalidToken { loc, text } => write!(f, "{}: invalid token `{}`", loc, text),
+            NoqError::UnexpectedToken {
+                loc,
+                expected,
+                found,
+            } => write!(f, "{}: expected {} but got {}", loc, expected, found),
+            NoqError::UnknownOperator { loc, symbol } => {
+                write!(f, "{}: unknown operator `{}`", loc, symbol)
+            }
+            NoqError::NoMatch { loc, rule, expr } => {
+                write!(f, "{}: rule `{}` does not match {}", loc, rule, expr)
+            }
+            NoqError::FunctorNotSymbol { loc, name, value } => {
+                if let Some(loc) = loc {
+                    write!(f, "{}: ", loc)?;
+                }
+                write!(
+                    f,
+                    "functor variable `{}` is bound to {}, which is not a symbol",
+                    name, value
+                )
+            }
+            NoqError::RepeatLimit { loc, limit } => {
+                if let Some(loc) = loc {
+                    write!(f, "{}: ", loc)?;
+                }
+                write!(f, "repeat did not stop after {} rounds", limit)
+            }
+            NoqError::NoSuchMatch { loc, n, count } => {
+                if let Some(loc) = loc {
+                    write!(f, "{}: ", loc)?;
+                }
+                write!(
+                    f,
+                    "no match number {}, the matches are numbered 1 to {}",
+                    n, count
+                )
+            }
+            NoqError::Command { loc, message } => write!(f, "{}: {}", loc, message),
+        }
+    }
+}
+
+/// An unexpected `token`, or the end of input if there is none.
+pub(crate) fn unexpected(loc: Loc, expected: impl Display, token: Option<Token>) -> NoqError {
+    match token {
+        Some(Token {
+            kind: TokenKind::Invalid,
+            text,
+            ..
+        }) => NoqError::InvalidToken { loc, text },
+        Some(token) => NoqError::UnexpectedToken {
+            loc,
+            expected: expected.to_string(),
+            found: token.kind.to_string(),
+        },
+        None => NoqError::UnexpectedToken {
+            loc,
+            expected: expected.to_string(),
+            found: "end of input".to_string(),
+        },
+    }
+}
+
+/// Consumes the next token, which has to be of `kind`.
+pub(crate) fn expect_token<Chars: Iterator<Item = char>>(
+    lexer: &mut Lexer<Chars>,
+    expected: TokenKind,
+) -> Result<Token, NoqError> {
+    let loc = lexer.loc();
+    match lexer.next() {
+        Some(token) if token.kind == expected => Ok(token),
+        token => Err(unexpected(loc, expected, token)),
+    }
+}
+
+/// Consumes the next token, which has to be a symbol, and returns its name.
+pub(crate) fn expect_sym<Chars: Iterator<Item = char>>(
+    lexer: &mut Lexer<Chars>,
+) -> Result<String, NoqError> {
+    let loc = lexer.loc();
+    match lexer.next() {
+        Some(Token {
+            kind: TokenKind::Sym(name),
+            ..
+        }) => Ok(name),
+        token => Err(unexpected(loc, "symbol", token)),
+    }
+}
+
+/// Consumes the next token, which has to be an operator, and returns it.
+pub(crate) fn expect_op<Chars: Iterator<Item = char>>(
+    lexer: &mut Lexer<Chars>,
+) -> Result<String, NoqError> {
+    let loc = lexer.loc();
+    match lexer.next() {
+        Some(Token {
+            kind: TokenKind::Op(symbol),
+            ..
+        }) => Ok(symbol),
+        token => Err(unexpected(loc, "operator", token)),
+    }
+}
+
+/// Fails unless all of the input was consumed.
+pub(crate) fn expect_end<Chars: Iterator<Item = char>>(
+    lexer: &mut Lexer<Chars>,
+) -> Result<(), NoqError> {
+    let loc = lexer.loc();
+    match lexer.next() {
+        None => Ok(()),
+        token => Err(unexpected(loc, "end of input", token)),
+    }
+}
+
+const COMPARISONS: [&str; 2] = ["==", "!="];
+const REVERSIBLE: &str = "<=>";
+
+/// Operators the grammar uses itself, which end an expression and so cannot
+/// be defined as infix functors.
+pub(crate) fn is_reserved_op(symbol: &str) -> bool {
+    symbol == REVERSIBLE || COMPARISONS.contains(&symbol)
+}
+
+impl Expr {
+    /// ```text
+    /// expr    := primary (op primary)*
+    /// primary := sym | var | ['-'] number | sym '(' [expr (',' expr)*] ')'
+    ///          | '(' expr ')'
+    /// ```
+    ///
+    /// Operators are resolved by precedence climbing over `operators`.
+    pub fn parse<Chars: Iterator<Item = char>>(
+        lexer: &mut Lexer<Chars>,
+        operators: &Operators,
+    ) -> Result<Self, NoqError> {
+        Self::parse_binary(lexer, operators, 0)
+    }
+
+    fn parse_binary<Chars: Iterator<Item = char>>(
+        lexer: &mut Lexer<Chars>,
+        operators: &Operators,
+        min_precedence: usize,
+    ) -> Result<Self, NoqError> {
+        let mut lhs = Self::parse_primary(lexer, operators)?;
+        loop {
+            lexer
+                .split_op(|symbol| is_reserved_op(symbol) || operators.by_symbol(symbol).is_some());
+            let loc = lexer.loc();
+            let op = match lexer.peek() {
+                // Comparisons end the expression, they only appear in guards,
+                // and so does the arrow of a reversible rule.
+                Some(Token {
+                    kind: TokenKind::Op(symbol),
+                    ..
+                }) if is_reserved_op(symbol) => return Ok(lhs),
+                Some(Token {
+                    kind: TokenKind::Op(symbol),
+                    ..
+                }) => operators
+                    .by_symbol(symbol)
+                    .ok_or_else(|| NoqError::UnknownOperator {
+                        loc,
+                        symbol: symbol.clone(),
+                    })?,
+                _ => return Ok(lhs),
+            };
+            if op.precedence < min_precedence {
+                return Ok(lhs);
+            }
+            lexer.next();
+            let next_precedence = match op.assoc {
+                Assoc::Left => op.precedence + 1,
+                Assoc::Right => op.precedence,
+            };
+            let rhs = Self::parse_binary(lexer, operators, next_precedence)?;
+            lhs = Expr::Fun(op.name.clone(), vec![lhs, rhs]);
+        }
+    }
+
+    fn parse_primary<Chars: Iterator<Item = char>>(
+        lexer: &mut Lexer<Chars>,
+        operators: &Operators,
+    ) -> Result<Self, NoqError> {
+        if matches!(lexer.peek(), Some(token) if token.kind == TokenKind::OpenParen) {
+            lexer.next();
+            let expr = Self::parse(lexer, operators)?;
+            expect_token(lexer, TokenKind::CloseParen)?;
+            return Ok(expr);
+        }
+        if let Some(number) = parse_literal(lexer)? {
+            return Ok(Expr::Num(number));
+        }
+
+        let name = expect_sym(lexer)?;
+
+        if !matches!(lexer.peek(), Some(token) if token.kind == TokenKind::OpenParen) {
+            if is_var_name(&name) {
+                return Ok(Expr::Var(name));
+            }
+            return Ok(Expr::Sym(name));
+        }
+        lexer.next();
+
+        let mut args = Vec::new();
+        if matches!(lexer.peek(), Some(token) if token.kind == TokenKind::CloseParen) {
+            lexer.next();
+            return Ok(Expr::Fun(name, args));
+        }
+        args.push(Self::parse(lexer, operators)?);
+        while matches!(lexer.peek(), Some(token) if token.kind == TokenKind::Comma) {
+            lexer.next();
+            args.push(Self::parse(lexer, operators)?);
+        }
+        expect_token(lexer, TokenKind::CloseParen)?;
+        Ok(Expr::Fun(name, args))
+    }
+}
+
+impl Rule {
+    /// ```text
+    /// rule  := expr ('=' | '<=>') expr ['if' guard (',' guard)*]
+    /// guard := expr ('==' | '!=') expr | 'number' '(' expr ')'
+    /// ```
+    pub fn parse<Chars: Iterator<Item = char>>(
+        lexer: &mut Lexer<Chars>,
+        operators: &Operators,
+    ) -> Result<Self, NoqError> {
+        let head = Expr::parse(lexer, operators)?;
+        let loc = lexer.loc();
+        let reversible = match lexer.next() {
+            Some(Token {
+                kind: TokenKind::Equals,
+                ..
+            }) => false,
+            Some(Token {
+                kind: TokenKind::Op(symbol),
+                ..
+            }) if symbol == REVERSIBLE => true,
+            token => return Err(unexpected(loc, "`=` or `<=>`", token)),
+        };
+        let body_loc = lexer.loc();
+        let body = Expr::parse(lexer, operators)?;
+        let head_vars = head.vars();
+        if let Some(var) = body.vars().into_iter().find(|var| !head_vars.contains(var)) {
+            return Err(NoqError::Command {
+                loc: body_loc,
+                message: format!("`{}` does not occur on the left, nothing binds it", var),
+            });
+        }
+        if reversible {
+            let body_vars = body.vars();
+            if let Some(var) = head.vars().into_iter().find(|var| !body_vars.contains(var)) {
+                return Err(NoqError::Command {
+                    loc,
+                    message: format!("cannot reverse rule, `{}` does not occur on the right", var),
+                });
+            }
+        }
+        let mut guards = Vec::new();
+        if matches!(lexer.peek(), Some(Token { kind: TokenKind::Sym(name), .. }) if name == "if") {
+            lexer.next();
+            guards.push(Guard::parse(lexer, operators)?);
+            while matches!(lexer.peek(), Some(token) if token.kind == TokenKind::Comma) {
+                lexer.next();
+                guards.push(Guard::parse(lexer, operators)?);
+            }
+        }
+        Ok(Rule {
+            head,
+            body,
+            guards,
+            reversible,
+        })
+    }
+}
+
+impl Guard {
+    /// Parses a single guard, a comparison or `number(expr)`.
+    pub fn parse<Chars: Iterator<Item = char>>(
+        lexer: &mut Lexer<Chars>,
+        operators: &Operators,
+    ) -> Result<Self, NoqError> {
+        let lhs = Expr::parse(lexer, operators)?;
+        let loc = lexer.loc();
+        let comparison = match lexer.peek() {
+            Some(Token {
+                kind: TokenKind::Op(symbol),
+                ..
+            }) if COMPARISONS.contains(&symbol.as_str()) => symbol.clone(),
+            _ => {
+                return match lhs {
+                    Expr::Fun(name, mut args) if name == "number" && args.len() == 1 => {
+                        Ok(Guard::Number(args.remove(0)))
+                    }
+                    _ => Err(unexpected(loc, "`==` or `!=`", lexer.next())),
+                }
+            }
+        };
+        lexer.next();
+        let rhs = Expr::parse(lexer, operators)?;
+        match comparison.as_str() {
+            "==" => Ok(Guard::Equal(lhs, rhs)),
+            _ => Ok(Guard::NotEqual(lhs, rhs)),
+        }
+    }
+}
+
+impl FromStr for Expr {
+    type Err = NoqError;
+    fn from_str(source: &str) -> Result<Self, Self::Err> {
+        let mut lexer = Lexer::new(source.chars());
+        let expr = Expr::parse(&mut lexer, default_operators())?;
+        expect_end(&mut lexer)?;
+        Ok(expr)
+    }
+}
+
+impl FromStr for Rule {
+    type Err = NoqError;
+    fn from_str(source: &str) -> Result<Self, Self::Err> {
+        let mut lexer = Lexer::new(source.chars());
+        let rule = Rule::parse(&mut lexer, default_operators())?;
+        expect_end(&mut lexer)?;
+        Ok(rule)
+    }
+}
+
+#[test]
+fn lexer_tracks_locations() {
+    let tokens: Vec<(TokenKind, Loc)> = Lexer::new("f(a,\n  b) = c".chars())
+        .map(|token| (token.kind, token.loc))
+        .collect();
+    let sym = |name: &str| TokenKind::Sym(name.to_string());
+    let loc = |row, col| Loc { row, col };
+    assert_eq!(
+        tokens,
+        vec![
+            (sym("f"), loc(1, 1)),
+            (TokenKind::OpenParen, loc(1, 2)),
+            (sym("a"), loc(1, 3)),
+            (TokenKind::Comma, loc(1, 4)),
+            (sym("b"), loc(2, 3)),
+            (TokenKind::CloseParen, loc(2, 4)),
+            (TokenKind::Equals, loc(2, 6)),
+            (sym("c"), loc(2, 8)),
+        ]
+    );
+}
+
+#[test]
+fn parse_rule() {
+    use Expr::*;
+    let rule: Rule = "swap(pair(a, b)) = pair(b, a)".parse().unwrap();
+    let pair = |x: &str, y: &str| {
+        Fun(
+            "pair".to_string(),
+            vec![Sym(x.to_string()), Sym(y.to_string())],
+        )
+    };
+    assert_eq!(rule.head, Fun("swap".to_string(), vec![pair("a", "b")]));
+    assert_eq!(rule.body, pair("b", "a"));
+    assert_eq!(rule.to_string(), "swap(pair(a, b)) = pair(b, a)");
+    assert_eq!("f()".parse::<Expr>().unwrap(), Fun("f".to_string(), vec![]));
+
+    let rule: Rule = "double(X) <=> X + X".parse().unwrap();
+    assert!(rule.reversible);
+    assert_eq!(rule.to_string(), "double(X) <=> X + X");
+    assert_eq!(
+        "f(X, Y) <=> g(X)".parse::<Rule>().unwrap_err().to_string(),
+        "1:9: cannot reverse rule, `Y` does not occur on the right"
+    );
+}
+
+#[test]
+fn infix_operators() {
+    let parse = |source: &str| source.parse::<Expr>().unwrap();
+    assert_eq!(parse("a + b * c"), parse("add(a, mul(b, c))"));
+    assert_eq!(parse("a - b - c"), parse("sub(sub(a, b), c)"));
+    assert_eq!(parse("a ^ b ^ c"), parse("pow(a, pow(b, c))"));
+    assert_eq!(
+        parse("(a + b) * f(c - d)"),
+        parse("mul(add(a, b), f(sub(c, d)))")
+    );
+
+    let round_trip = |source: &str| parse(source).to_string();
+    assert_eq!(round_trip("add(a, mul(b, c))"), "a + b * c");
+    assert_eq!(round_trip("mul(add(a, b), c)"), "(a + b) * c");
+    assert_eq!(round_trip("sub(a, sub(b, c))"), "a - (b - c)");
+    assert_eq!(round_trip("sub(sub(a, b), c)"), "a - b - c");
+    assert_eq!(round_trip("pow(pow(a, b), c)"), "(a ^ b) ^ c");
+    assert_eq!(round_trip("pow(a, pow(b, c))"), "a ^ b ^ c");
+    assert_eq!(round_trip("add(a, b, c)"), "add(a, b, c)");
+
+    let mut operators = Operators::default();
+    operators.define(Operator {
+        symbol: "-".to_string(),
+        name: "sub".to_string(),
+        precedence: 1,
+        assoc: Assoc::Right,
+    });
+    let mut lexer = Lexer::new("a - b - c".chars());
+    let expr = Expr::parse(&mut lexer, &operators).unwrap();
+    assert_eq!(expr, parse("sub(a, sub(b, c))"));
+    assert_eq!(operators.show(&expr).to_string(), "a - b - c");
+
+    assert_eq!(
+        "a <> b".parse::<Expr>().unwrap_err().to_string(),
+        "1:3: unknown operator `<>`"
+    );
+}
+
+#[test]
+fn parse_numbers() {
+    let parse = |source: &str| source.parse::<Expr>().unwrap();
+    let num = |num, den| Expr::Num(Number::new(num, den).unwrap());
+    assert_eq!(parse("42"), num(42, 1));
+    assert_eq!(parse("6/4"), num(3, 2));
+    assert_eq!(parse("-1/2"), num(-1, 2));
+    assert_eq!(parse("1 / 2"), parse("div(1, 2)"));
+    assert_eq!(parse("1/x"), parse("div(1, x)"));
+    assert_eq!(
+        parse("a - -3"),
+        Expr::Fun("sub".to_string(), vec![parse("a"), num(-3, 1)])
+    );
+
+    let round_trip = |source: &str| parse(source).to_string();
+    assert_eq!(round_trip("f(-2, 2/4) * 1/3"), "f(-2, 1/2) * 1/3");
+    assert_eq!(round_trip("div(1, 3)"), "1 / 3");
+    // A minus right after an operator negates the number that follows.
+    assert_eq!(round_trip("a*-1"), "a * -1");
+    assert_eq!(round_trip("2^-1"), "2 ^ -1");
+    assert_eq!(round_trip("1/-2"), "1 / -2");
+    assert_eq!(round_trip("a--1"), "a - -1");
+    let rule: Rule = "f(X)=-1 if X!=-1".parse().unwrap();
+    assert_eq!(rule.to_string(), "f(X) = -1 if X != -1");
+
+    let error = |source: &str| source.parse::<Expr>().unwrap_err().to_string();
+    assert_eq!(error("f(1/0)"), "1:3: invalid token `1/0`");
+    assert_eq!(error("2x"), "1:1: invalid token `2x`");
+    assert_eq!(
+        error("99999999999999999999"),
+        "1:1: invalid token `99999999999999999999`"
+    );
+    assert_eq!(error("- a"), "1:3: expected number but got symbol `a`");
+
+    assert_eq!(parse("-9223372036854775808"), num(i64::MIN, 1));
+    assert_eq!(parse("18446744073709551614/2"), num(i64::MAX, 1));
+    assert_eq!(
+        error("9223372036854775808"),
+        "1:1: number 9223372036854775808 does not fit"
+    );
+}
+
+#[test]
+fn parse_guards() {
+    let rule: Rule = "div(X, X) = one if X != zero, number(X)".parse().unwrap();
+    assert_eq!(
+        rule.guards,
+        [
+            Guard::NotEqual("X".parse().unwrap(), "zero".parse().unwrap()),
+            Guard::Number("X".parse().unwrap()),
+        ]
+    );
+    assert_eq!(rule.to_string(), "X / X = one if X != zero, number(X)");
+    let rule: Rule = "f(X, Y) = g if X + Y == Y + X".parse().unwrap();
+    assert_eq!(rule.to_string(), "f(X, Y) = g if X + Y == Y + X");
+
+    let error = "f(X) = g if X".parse::<Rule>().unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "1:14: expected `==` or `!=` but got end of input"
+    );
+    let error = "f(X) = g if X != ".parse::<Rule>().unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "1:18: expected symbol but got end of input"
+    );
+}
+
+#[test]
+fn parse_errors_point_at_location() {
+    let error = "f(a,\n  b c)".parse::<Expr>().unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "2:5: expected close paren but got symbol `c`"
+    );
+    let error = "f(a".parse::<Expr>().unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "1:4: expected close paren but got end of input"
+    );
+    let error = "f(a) = ".parse::<Rule>().unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "1:8: expected symbol but got end of input"
+    );
+    let error = "f(a) ; g".parse::<Rule>().unwrap_err();
+    assert_eq!(error.to_string(), "1:6: invalid token `;`");
+    let error = "F(X, Y) = G(Y)".parse::<Rule>().unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "1:11: `G` does not occur on the left, nothing binds it"
+    );
+}
+
+/// ```text
+/// strategy := 'first' | 'topdown' | 'bottomup' | 'at' index* | number
+/// ```
+///
+/// Without a strategy the rule is applied everywhere top-down. A number picks
+/// one of the matches listed by `matches`.
+pub fn parse_strategy<Chars: Iterator<Item = char>>(
+    lexer: &mut Lexer<Chars>,
+) -> Result<Strategy, NoqError> {
+    if let Some(n) = parse_number(lexer) {
+        return Ok(Strategy::Match(n));
+    }
+    let strategy = lexer.next_sym_if(|name| match name {
+        "first" => Some(Strategy::First),
+        "topdown" => Some(Strategy::TopDown),
+        "bottomup" => Some(Strategy::BottomUp),
+        "at" => Some(Strategy::At(Vec::new())),
+        _ => None,
+    });
+    let Some(strategy) = strategy else {
+        return Ok(Strategy::TopDown);
+    };
+    if let Strategy::At(mut path) = strategy {
+        while let Some(index) = parse_number(lexer) {
+            path.push(index);
+        }
+        return Ok(Strategy::At(path));
+    }
+    Ok(strategy)
+}
+
+/// Consumes the next token only if it is a number.
+pub(crate) fn parse_number<Chars: Iterator<Item = char>>(
+    lexer: &mut Lexer<Chars>,
+) -> Option<usize> {
+    let number = match lexer.peek() {
+        Some(Token {
+            kind: TokenKind::Num(num, 1),
+            ..
+        }) => (*num).try_into().ok()?,
+        _ => return None,
+    };
+    lexer.next();
+    Some(number)
+}
+
+// A number, negated if it comes after a minus. There is no other prefix
+// operator, so a minus in the place of an operand can only mean that.
+fn parse_literal<Chars: Iterator<Item = char>>(
+    lexer: &mut Lexer<Chars>,
+) -> Result<Option<Number>, NoqError> {
+    let negative =
+        matches!(lexer.peek(), Some(Token { kind: TokenKind::Op(symbol), .. }) if symbol == "-");
+    if negative {
+        lexer.next();
+    }
+    let loc = lexer.loc();
+    match lexer.peek() {
+        Some(Token {
+            kind: TokenKind::Num(num, den),
+            text,
+            ..
+        }) => {
+            let number = Number::from_literal(negative, *num, *den);
+            let text = format!("{}{}", if negative { "-" } else { "" }, text);
+            lexer.next();
+            number.map(Some).ok_or(NoqError::Command {
+                loc,
+                message: format!("number {} does not fit", text),
+            })
+        }
+        _ if negative => Err(unexpected(loc, "number", lexer.next())),
+        _ => Ok(None),
+    }
+}
+
+/// One rewrite along a derivation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Step {
+    /// The rule applied, or `eval` for built-in arithmetic.
+    pub rule_name: String,
+    /// Where the rule was applied, more than one path if the strategy
+    /// rewrote several subexpressions at once.
+    pub paths: Vec<Path>,
+    /// The expression after the step.
+    pub expr: Expr,
+}
+
+/// Why normalization stopped.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stop {
+    /// No rule matches anymore.
+    Normal,
+    /// The maximum number of steps was reached.
+    StepLimit,
+    /// The next step would produce an expression that was already seen.
+    Cycle,
+}
+
+/// The steps `normalize` took and how it ended.
+#[derive(Debug)]
+pub struct Normalization {
+    /// The steps in order, the last one giving the result.
+    pub steps: Vec<Step>,
+    /// Why it stopped.
+    pub stop: Stop,
+    /// How much the rule index saved.
+    pub stats: IndexStats,
+}
+
+/// How many steps normalization takes before giving up by default.
+pub const DEFAULT_MAX_STEPS: usize = 1000;
+
+/// Rewrites `expr` one redex at a time until no rule matches. Each step
+/// rewrites the leftmost-outermost redex, with the first rule in definition
+/// order that matches there, so the steps read the same way as a derivation
+/// done by hand with `apply <rule> first`. Rules are matched modulo
+/// `theories`, taking the first match.
+pub fn normalize(
+    rules: &[(String, Rule)],
+    theories: &Theories,
+    expr: &Expr,
+    max_steps: usize,
+) -> Result<Normalization, NoqError> {
+    let mut arena = Arena::default();
+    let mut stats = IndexStats::default();
+    let (steps, stop) =
+        normalize_in_arena(&mut arena, rules, theories, expr, max_steps, &mut stats)?;
+    Ok(Normalization {
+        steps: steps
+            .into_iter()
+            .map(|(rule, path, id)| Step {
+                rule_name: match rule {
+                    Some(rule) => rules[rule].0.clone(),
+                    None => number::EVAL_RULE.to_string(),
+                },
+                paths: vec![path],
+                expr: arena.to_expr(id),
+            })
+            .collect(),
+        stop,
+        stats,
+    })
+}
+
+/// The normal form of `expr`, or the last expression reached if rewriting did
+/// not terminate. Matches syntactically, without any theories.
+pub fn normal_form(
+    rules: &[(String, Rule)],
+    expr: &Expr,
+    max_steps: usize,
+) -> Result<Result<Expr, Expr>, NoqError> {
+    let mut arena = Arena::default();
+    let mut stats = IndexStats::default();
+    let theories = Theories::new();
+    let (steps, stop) =
+        normalize_in_arena(&mut arena, rules, &theories, expr, max_steps, &mut stats)?;
+    let last = match steps.last() {
+        Some(&(_, _, id)) => arena.to_expr(id),
+        None => expr.clone(),
+    };
+    match stop {
+        Stop::Normal => Ok(Ok(last)),
+        Stop::StepLimit | Stop::Cycle => Ok(Err(last)),
+    }
+}
+
+/// The loop behind `normalize` and `normal_form`, on expressions interned in
+/// `arena` and with the rules indexed by their heads.
+pub(crate) fn normalize_in_arena(
+    arena: &mut Arena,
+    rules: &[(String, Rule)],
+    theories: &Theories,
+    expr: &Expr,
+    max_steps: usize,
+    stats: &mut IndexStats,
+) -> Result<(Vec<IdStep>, Stop), NoqError> {
+    let rules: Vec<IdRule> = rules
+        .iter()
+        .map(|(_, rule)| arena.intern_rule(rule))
+        .collect();
+    let expr = arena.intern(expr);
+    arena.normalize(&rules, theories, expr, max_steps, stats)
+}
+
+#[test]
+fn normalize_to_fixpoint() {
+    let rules: Vec<(String, Rule)> = [
+        ("add_zero", "add(zero, X) = X"),
+        ("add_succ", "add(succ(X), Y) = succ(add(X, Y))"),
+        ("comm", "mul(X, Y) = mul(Y, X)"),
+    ]
+    .iter()
+    .map(|(name, rule)| (name.to_string(), rule.parse().unwrap()))
+    .collect();
+
+    let expr = "add(succ(succ(zero)), succ(zero))".parse().unwrap();
+    let normalization = normalize(&rules, &Theories::new(), &expr, DEFAULT_MAX_STEPS).unwrap();
+    assert_eq!(normalization.stop, Stop::Normal);
+    let fired: Vec<&str> = normalization
+        .steps
+        .iter()
+        .map(|step| step.rule_name.as_str())
+        .collect();
+    assert_eq!(fired, ["add_succ", "add_succ", "add_zero"]);
+    let paths: Vec<&[Path]> = normalization
+        .steps
+        .iter()
+        .map(|step| step.paths.as_slice())
+        .collect();
+    assert_eq!(paths, [[vec![]], [vec![0]], [vec![0, 0]]]);
+    assert_eq!(
+        normalization.steps.last().unwrap().expr.to_string(),
+        "succ(succ(succ(zero)))"
+    );
+
+    let normalization = normalize(&rules, &Theories::new(), &expr, 2).unwrap();
+    assert_eq!(normalization.stop, Stop::StepLimit);
+    assert_eq!(normalization.steps.len(), 2);
+
+    let normalization = normalize(
+        &rules,
+        &Theories::new(),
+        &"mul(a, b)".parse().unwrap(),
+        DEFAULT_MAX_STEPS,
+    )
+    .unwrap();
+    assert_eq!(normalization.stop, Stop::Cycle);
+    assert_eq!(normalization.steps.len(), 1);
+}
+
+#[test]
+fn normalize_compares_repeated_variables_modulo_theories() {
+    let rules: Vec<(String, Rule)> = vec![("r".to_string(), "f(X, X) = X".parse().unwrap())];
+    for (name, theory, expr) in [
+        ("add", theory::Theory::AC, "f(a + b, b + a)"),
+        ("mul", theory::Theory::C, "f(a * b, b * a)"),
+    ] {
+        let mut theories = Theories::new();
+        theories.insert(name.to_string(), theory);
+        let normalization =
+            normalize(&rules, &theories, &expr.parse().unwrap(), DEFAULT_MAX_STEPS).unwrap();
+        assert_eq!(normalization.stop, Stop::Normal);
+        assert_eq!(normalization.steps.len(), 1);
+    }
+}
+
+#[test]
+fn evaluate_arithmetic() {
+    let rules: Vec<(String, Rule)> = vec![("half".to_string(), "half(X) = X / 2".parse().unwrap())];
+    let run = |expr: &str| {
+        normalize(
+            &rules,
+            &Theories::new(),
+            &expr.parse().unwrap(),
+            DEFAULT_MAX_STEPS,
+        )
+        .unwrap()
+    };
+
+    let normalization = run("add(2, 3)");
+    assert_eq!(normalization.stop, Stop::Normal);
+    assert_eq!(normalization.steps.len(), 1);
+    assert_eq!(normalization.steps[0].rule_name, number::EVAL_RULE);
+    assert_eq!(normalization.steps[0].expr.to_string(), "5");
+
+    // User rules and arithmetic take turns, innermost numbers first since
+    // only an operator applied to two numbers folds.
+    let normalization = run("half(1 - 1/3) * (2 + x)");
+    let fired: Vec<&str> = normalization
+        .steps
+        .iter()
+        .map(|step| step.rule_name.as_str())
+        .collect();
+    assert_eq!(fired, ["half", "eval", "eval"]);
+    assert_eq!(
+        normalization.steps.last().unwrap().expr.to_string(),
+        "1/3 * (2 + x)"
+    );
+
+    // Division by zero is left alone.
+    assert!(run("1 / 0").steps.is_empty());
+}
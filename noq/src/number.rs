@@ -0,0 +1,154 @@
+use std::{cmp::Ordering, fmt::Display};
+
+/// An exact rational number, always in lowest terms with a positive
+/// denominator, so equal numbers are equal values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Number {
+    num: i64,
+    den: i64,
+}
+
+fn gcd(mut a: i128, mut b: i128) -> i128 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a.abs()
+}
+
+impl Number {
+    /// The integer `num`.
+    pub fn integer(num: i64) -> Self {
+        Number { num, den: 1 }
+    }
+
+    /// None if `den` is zero or the result does not fit.
+    pub fn new(num: i64, den: i64) -> Option<Self> {
+        Self::reduce(num as i128, den as i128)
+    }
+
+    /// The literal `num/den`, negated if `negative`. None if `den` is zero
+    /// or the result does not fit.
+    pub fn from_literal(negative: bool, num: u64, den: u64) -> Option<Self> {
+        let num = num as i128;
+        Self::reduce(if negative { -num } else { num }, den as i128)
+    }
+
+    fn reduce(num: i128, den: i128) -> Option<Self> {
+        if den == 0 {
+            return None;
+        }
+        let divisor = gcd(num, den) * den.signum();
+        Some(Number {
+            num: (num / divisor).try_into().ok()?,
+            den: (den / divisor).try_into().ok()?,
+        })
+    }
+
+    /// The value if it is an integer.
+    pub fn as_integer(&self) -> Option<i64> {
+        (self.den == 1).then_some(self.num)
+    }
+
+    /// The negation, None on overflow.
+    pub fn checked_neg(self) -> Option<Self> {
+        Some(Number {
+            num: self.num.checked_neg()?,
+            den: self.den,
+        })
+    }
+
+    /// The sum. The arithmetic operations return None on overflow and
+    /// division by zero.
+    pub fn checked_add(self, other: Self) -> Option<Self> {
+        let (a, b, c, d) = self.widen(other);
+        Self::reduce(a * d + c * b, b * d)
+    }
+
+    /// The difference.
+    pub fn checked_sub(self, other: Self) -> Option<Self> {
+        let (a, b, c, d) = self.widen(other);
+        Self::reduce(a * d - c * b, b * d)
+    }
+
+    /// The product.
+    pub fn checked_mul(self, other: Self) -> Option<Self> {
+        let (a, b, c, d) = self.widen(other);
+        Self::reduce(a * c, b * d)
+    }
+
+    /// The quotient.
+    pub fn checked_div(self, other: Self) -> Option<Self> {
+        let (a, b, c, d) = self.widen(other);
+        Self::reduce(a * d, b * c)
+    }
+
+    fn widen(self, other: Self) -> (i128, i128, i128, i128) {
+        (
+            self.num as i128,
+            self.den as i128,
+            other.num as i128,
+            other.den as i128,
+        )
+    }
+}
+
+// By value, the derived order on the fields would put 1/2 before 1/3.
+impl Ord for Number {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let (a, b, c, d) = self.widen(*other);
+        (a * d).cmp(&(c * b))
+    }
+}
+
+impl PartialOrd for Number {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Display for Number {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::result::Result<(), std::fmt::Error> {
+        if self.den == 1 {
+            write!(f, "{}", self.num)
+        } else {
+            write!(f, "{}/{}", self.num, self.den)
+        }
+    }
+}
+
+/// The rule name steps of built-in arithmetic are recorded under.
+pub const EVAL_RULE: &str = "eval";
+
+/// The built-in arithmetic on number literals, by the functor names of the
+/// default operators. None if the functor is not one of them or the result is
+/// undefined.
+pub fn evaluate(name: &str, a: Number, b: Number) -> Option<Number> {
+    match name {
+        "add" => a.checked_add(b),
+        "sub" => a.checked_sub(b),
+        "mul" => a.checked_mul(b),
+        "div" => a.checked_div(b),
+        _ => None,
+    }
+}
+
+#[test]
+fn rational_arithmetic() {
+    let n = |num, den| Number::new(num, den).unwrap();
+    assert_eq!(n(2, 4), n(1, 2));
+    assert_eq!(n(1, -2), n(-1, 2));
+    assert_eq!(n(1, 2).checked_add(n(1, 3)), Some(n(5, 6)));
+    assert_eq!(n(1, 2).checked_sub(n(1, 2)), Some(Number::integer(0)));
+    assert_eq!(n(2, 3).checked_mul(n(3, 4)), Some(n(1, 2)));
+    assert_eq!(n(1, 2).checked_div(n(1, 4)), Some(Number::integer(2)));
+    assert_eq!(n(1, 2).checked_div(Number::integer(0)), None);
+    assert_eq!(
+        Number::integer(i64::MAX).checked_add(Number::integer(1)),
+        None
+    );
+    assert!(n(1, 2) > n(1, 3));
+    assert!(n(-1, 2) < n(1, 3));
+    assert!(Number::integer(i64::MIN) < Number::integer(i64::MAX));
+    assert_eq!(n(-3, 6).to_string(), "-1/2");
+    assert_eq!(n(6, 3).to_string(), "2");
+}
@@ -0,0 +1,396 @@
+use std::{cmp::Ordering, collections::HashMap};
+
+use crate::{number::Number, Expr, Rule};
+
+/// Functor names from the greatest to the least. Names that are not listed
+/// are incomparable with every other name.
+#[derive(Debug, Clone, Default)]
+pub struct Precedence(pub Vec<String>);
+
+impl Precedence {
+    fn compare(&self, name1: &str, name2: &str) -> Option<Ordering> {
+        if name1 == name2 {
+            return Some(Ordering::Equal);
+        }
+        let index1 = self.0.iter().position(|name| name == name1)?;
+        let index2 = self.0.iter().position(|name| name == name2)?;
+        // Earlier in the list means greater.
+        Some(index2.cmp(&index1))
+    }
+}
+
+/// A well-founded ordering on expressions that is closed under substitution
+/// and contexts, so rules with `head > body` always terminate.
+pub trait TermOrder {
+    /// Whether `s` is strictly greater than `t`.
+    fn greater(&self, s: &Expr, t: &Expr) -> bool;
+
+    /// Fails if the ordering is not well-founded on the functors `rule` uses,
+    /// in which case it cannot be used to show that the rule terminates.
+    fn check(&self, _rule: &Rule) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// Which way a term order lets a rule go.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Orientation {
+    /// head > body
+    Forward,
+    /// body > head, the rule terminates if flipped
+    Backward,
+    /// neither side is greater
+    Unorientable,
+}
+
+/// Orients `rule` with `order`.
+pub fn orientation(order: &dyn TermOrder, rule: &Rule) -> Orientation {
+    if order.greater(&rule.head, &rule.body) {
+        Orientation::Forward
+    } else if order.greater(&rule.body, &rule.head) {
+        Orientation::Backward
+    } else {
+        Orientation::Unorientable
+    }
+}
+
+/// Lexicographic path ordering.
+#[derive(Debug, Clone, Default)]
+pub struct Lpo {
+    /// Functors from the greatest to the least.
+    pub precedence: Precedence,
+}
+
+impl TermOrder for Lpo {
+    fn greater(&self, s: &Expr, t: &Expr) -> bool {
+        lpo_greater(&self.precedence, s, t)
+    }
+}
+
+/// Knuth-Bendix ordering. Variables weigh 1, as does every functor missing
+/// from `weights`. At most one unary functor may weigh 0 and it has to be the
+/// greatest in the precedence, otherwise the ordering is not well-founded.
+/// `Kbo::new` checks the precedence, `check` that the functor is unary.
+#[derive(Debug, Clone, Default)]
+pub struct Kbo {
+    precedence: Precedence,
+    weights: HashMap<String, usize>,
+}
+
+impl TermOrder for Kbo {
+    fn greater(&self, s: &Expr, t: &Expr) -> bool {
+        kbo_greater(self, s, t)
+    }
+
+    fn check(&self, rule: &Rule) -> Result<(), String> {
+        self.check_weights(&rule.head)?;
+        self.check_weights(&rule.body)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Head<'a> {
+    Name(&'a str),
+    Num(Number),
+}
+
+// Constants and numbers are treated as functors without arguments.
+fn functor(expr: &Expr) -> Option<(Head<'_>, &[Expr])> {
+    match expr {
+        Expr::Sym(name) => Some((Head::Name(name), &[])),
+        Expr::Num(number) => Some((Head::Num(*number), &[])),
+        Expr::Fun(name, args) => Some((Head::Name(name), args)),
+        Expr::Var(_) => None,
+    }
+}
+
+// Numbers are less than every name in the precedence and incomparable with
+// each other.
+fn compare_heads(precedence: &Precedence, f: Head, g: Head) -> Option<Ordering> {
+    match (f, g) {
+        (Head::Name(f), Head::Name(g)) => precedence.compare(f, g),
+        (Head::Name(_), Head::Num(_)) => Some(Ordering::Greater),
+        (Head::Num(_), Head::Name(_)) => Some(Ordering::Less),
+        (Head::Num(a), Head::Num(b)) => (a == b).then_some(Ordering::Equal),
+    }
+}
+
+fn contains_var(expr: &Expr, name: &str) -> bool {
+    match expr {
+        Expr::Var(var) => var == name,
+        Expr::Sym(_) | Expr::Num(_) => false,
+        Expr::Fun(_, args) => args.iter().any(|arg| contains_var(arg, name)),
+    }
+}
+
+fn lpo_greater(precedence: &Precedence, s: &Expr, t: &Expr) -> bool {
+    let Some((f, ss)) = functor(s) else {
+        // A variable is never greater than anything.
+        return false;
+    };
+    let (g, ts) = match functor(t) {
+        Some(functor) => functor,
+        None => {
+            let Expr::Var(name) = t else { unreachable!() };
+            return contains_var(s, name);
+        }
+    };
+    if ss
+        .iter()
+        .any(|si| si == t || lpo_greater(precedence, si, t))
+    {
+        return true;
+    }
+    match compare_heads(precedence, f, g) {
+        Some(Ordering::Greater) => ts.iter().all(|tj| lpo_greater(precedence, s, tj)),
+        Some(Ordering::Equal) if ss.len() == ts.len() => {
+            ts.iter().all(|tj| lpo_greater(precedence, s, tj))
+                && lex_greater(ss, ts, |si, ti| lpo_greater(precedence, si, ti))
+        }
+        _ => false,
+    }
+}
+
+fn lex_greater(ss: &[Expr], ts: &[Expr], greater: impl Fn(&Expr, &Expr) -> bool) -> bool {
+    match ss.iter().zip(ts).find(|(si, ti)| si != ti) {
+        Some((si, ti)) => greater(si, ti),
+        None => false,
+    }
+}
+
+impl Kbo {
+    /// The ordering with `precedence` and `weights`, failing if the weights
+    /// of 0 break the constraints on the precedence.
+    pub fn new(precedence: Precedence, weights: HashMap<String, usize>) -> Result<Self, String> {
+        let mut weightless: Vec<&String> = weights
+            .iter()
+            .filter(|(_, &weight)| weight == 0)
+            .map(|(name, _)| name)
+            .collect();
+        weightless.sort();
+        match weightless.as_slice() {
+            [] => {}
+            [name] if precedence.0.first() == Some(*name) => {}
+            [name] => {
+                return Err(format!(
+                    "`{}` weighs 0, so it has to be the greatest in the precedence",
+                    name
+                ))
+            }
+            [first, second, ..] => {
+                return Err(format!(
+                    "only one functor can weigh 0, not both `{}` and `{}`",
+                    first, second
+                ))
+            }
+        }
+        Ok(Kbo {
+            precedence,
+            weights,
+        })
+    }
+
+    fn check_weights(&self, expr: &Expr) -> Result<(), String> {
+        match expr {
+            Expr::Var(_) | Expr::Num(_) => Ok(()),
+            Expr::Sym(name) if self.weights.get(name) == Some(&0) => {
+                Err(format!("constant `{}` has to weigh more than 0", name))
+            }
+            Expr::Sym(_) => Ok(()),
+            Expr::Fun(name, args) => {
+                if self.weights.get(name) == Some(&0) && args.len() != 1 {
+                    return Err(format!(
+                        "`{}` weighs 0, so it has to take one argument, not {}",
+                        name,
+                        args.len()
+                    ));
+                }
+                args.iter().try_for_each(|arg| self.check_weights(arg))
+            }
+        }
+    }
+
+    fn weight(&self, expr: &Expr) -> usize {
+        let functor_weight = |name: &str| self.weights.get(name).copied().unwrap_or(1);
+        match expr {
+            Expr::Var(_) | Expr::Num(_) => 1,
+            Expr::Sym(name) => functor_weight(name),
+            Expr::Fun(name, args) => {
+                functor_weight(name) + args.iter().map(|arg| self.weight(arg)).sum::<usize>()
+            }
+        }
+    }
+}
+
+// Whether `s` is `f(f(...f(t)))` for a unary `f`, with `t` a variable.
+fn is_unary_tower(s: &Expr, t: &Expr) -> bool {
+    let Expr::Fun(f, args) = s else {
+        return false;
+    };
+    let mut current = s;
+    while let Expr::Fun(name, args) = current {
+        if name != f || args.len() != 1 {
+            return false;
+        }
+        current = &args[0];
+    }
+    args.len() == 1 && current == t && matches!(t, Expr::Var(_))
+}
+
+fn var_counts(expr: &Expr, counts: &mut Vec<(String, usize)>) {
+    match expr {
+        Expr::Var(name) => match counts.iter_mut().find(|(var, _)| var == name) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((name.clone(), 1)),
+        },
+        Expr::Sym(_) | Expr::Num(_) => {}
+        Expr::Fun(_, args) => {
+            for arg in args {
+                var_counts(arg, counts);
+            }
+        }
+    }
+}
+
+fn kbo_greater(kbo: &Kbo, s: &Expr, t: &Expr) -> bool {
+    // Every variable has to occur in `s` at least as often as in `t`,
+    // otherwise substituting a big enough term would flip the comparison.
+    let mut s_counts = Vec::new();
+    let mut t_counts = Vec::new();
+    var_counts(s, &mut s_counts);
+    var_counts(t, &mut t_counts);
+    let vars_ok = t_counts.iter().all(|(name, t_count)| {
+        s_counts
+            .iter()
+            .any(|(var, s_count)| var == name && s_count >= t_count)
+    });
+    if !vars_ok {
+        return false;
+    }
+
+    let (s_weight, t_weight) = (kbo.weight(s), kbo.weight(t));
+    if s_weight != t_weight {
+        return s_weight > t_weight;
+    }
+    if is_unary_tower(s, t) {
+        return true;
+    }
+    let (Some((f, ss)), Some((g, ts))) = (functor(s), functor(t)) else {
+        return false;
+    };
+    match compare_heads(&kbo.precedence, f, g) {
+        Some(Ordering::Greater) => true,
+        Some(Ordering::Equal) if ss.len() == ts.len() => {
+            lex_greater(ss, ts, |si, ti| kbo_greater(kbo, si, ti))
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+fn precedence(names: &[&str]) -> Precedence {
+    Precedence(names.iter().map(|name| name.to_string()).collect())
+}
+
+#[test]
+fn lpo_orients_rules() {
+    let order = Lpo {
+        precedence: precedence(&["i", "mul", "e"]),
+    };
+    let greater = |s: &str, t: &str| order.greater(&s.parse().unwrap(), &t.parse().unwrap());
+    assert!(greater("mul(mul(X, Y), Z)", "mul(X, mul(Y, Z))"));
+    assert!(!greater("mul(X, mul(Y, Z))", "mul(mul(X, Y), Z)"));
+    assert!(greater("i(mul(X, Y))", "mul(i(Y), i(X))"));
+    assert!(greater("mul(e, X)", "X"));
+    assert!(greater("i(e)", "e"));
+    assert!(!greater("f(X)", "g(X)"));
+    assert!(!greater("mul(X, Y)", "mul(Y, X)"));
+    assert!(!greater("X", "Y"));
+}
+
+#[test]
+fn kbo_orients_rules() {
+    let order = Kbo {
+        precedence: precedence(&["i", "mul", "e"]),
+        weights: HashMap::new(),
+    };
+    let greater = |s: &str, t: &str| order.greater(&s.parse().unwrap(), &t.parse().unwrap());
+    assert!(greater("mul(mul(X, Y), Z)", "mul(X, mul(Y, Z))"));
+    assert!(greater("mul(i(X), X)", "e"));
+    assert!(greater("i(i(X))", "X"));
+    assert!(!greater("f(X, X)", "g(X, X, X)"));
+    assert!(!greater("f(X)", "g(X, X)"));
+    assert!(!greater("mul(X, Y)", "mul(Y, X)"));
+}
+
+#[test]
+fn kbo_with_weights() {
+    // A weightless unary functor at the top of the precedence.
+    let order = Kbo {
+        precedence: precedence(&["i", "mul", "e"]),
+        weights: HashMap::from([("i".to_string(), 0)]),
+    };
+    let greater = |s: &str, t: &str| order.greater(&s.parse().unwrap(), &t.parse().unwrap());
+    assert!(greater("i(X * Y)", "i(Y) * i(X)"));
+    assert!(greater("i(i(X))", "X"));
+    assert!(greater("i(X)", "X"));
+    assert!(greater("i(e)", "e"));
+
+    let order = Kbo {
+        precedence: Precedence::default(),
+        weights: HashMap::from([("f".to_string(), 3)]),
+    };
+    let greater = |s: &str, t: &str| order.greater(&s.parse().unwrap(), &t.parse().unwrap());
+    assert!(greater("f(X)", "g(g(X))"));
+    assert!(!greater("g(g(X))", "f(X)"));
+}
+
+#[test]
+fn kbo_rejects_weights_that_do_not_terminate() {
+    let kbo = |names: &[&str], weights: &[(&str, usize)]| {
+        let weights = weights
+            .iter()
+            .map(|(name, weight)| (name.to_string(), *weight))
+            .collect();
+        Kbo::new(precedence(names), weights)
+    };
+    assert_eq!(
+        kbo(&["f", "g"], &[("f", 0), ("g", 0)]).unwrap_err(),
+        "only one functor can weigh 0, not both `f` and `g`"
+    );
+    assert_eq!(
+        kbo(&["f", "g"], &[("g", 0)]).unwrap_err(),
+        "`g` weighs 0, so it has to be the greatest in the precedence"
+    );
+    let order = kbo(&["f", "g"], &[("f", 0)]).unwrap();
+    let check = |rule: &str| order.check(&rule.parse().unwrap());
+    assert_eq!(check("f(g(X)) = g(X)"), Ok(()));
+    assert_eq!(
+        check("f(X, Y) = X").unwrap_err(),
+        "`f` weighs 0, so it has to take one argument, not 2"
+    );
+    assert_eq!(
+        check("g(f) = f").unwrap_err(),
+        "constant `f` has to weigh more than 0"
+    );
+}
+
+#[test]
+fn orientation_of_rules() {
+    let order = Lpo {
+        precedence: precedence(&["f", "g"]),
+    };
+    let orient = |rule: &str| orientation(&order, &rule.parse().unwrap());
+    assert_eq!(orient("f(X) = g(X)"), Orientation::Forward);
+    assert_eq!(orient("g(X) = f(X)"), Orientation::Backward);
+    assert_eq!(orient("h(X) = g(X)"), Orientation::Unorientable);
+    // Equations found by completion can have variables only on the right,
+    // which the parser does not accept in rules.
+    let free = Rule {
+        head: "f(X)".parse().unwrap(),
+        body: "Y".parse().unwrap(),
+        guards: Vec::new(),
+        reversible: false,
+    };
+    assert_eq!(orientation(&order, &free), Orientation::Unorientable);
+}
@@ -0,0 +1,193 @@
+use std::fmt::Write;
+
+use crate::{Expr, Operators, Path, Step};
+
+/// How `export` writes a derivation.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// Numbered steps, each with the expression before and after.
+    Text,
+    /// A table with a row per step.
+    Markdown,
+    /// An object with the steps in an array.
+    Json,
+}
+
+impl Format {
+    /// The format called `name` in the REPL.
+    pub fn by_name(name: &str) -> Option<Self> {
+        match name {
+            "text" => Some(Format::Text),
+            "markdown" => Some(Format::Markdown),
+            "json" => Some(Format::Json),
+            _ => None,
+        }
+    }
+}
+
+/// The derivation of the last step's expression from `start`, one step at a
+/// time, each with the rule that was applied, where, and the expressions
+/// before and after.
+pub fn export(start: &Expr, steps: &[Step], operators: &Operators, format: Format) -> String {
+    let show = |expr: &Expr| operators.show(expr).to_string();
+    let mut befores = Vec::new();
+    let mut before = start;
+    for step in steps {
+        befores.push(before);
+        before = &step.expr;
+    }
+    let result = before;
+
+    let mut out = String::new();
+    match format {
+        Format::Text => {
+            writeln!(out, "start: {}", show(start)).unwrap();
+            for (i, (step, before)) in steps.iter().zip(befores).enumerate() {
+                writeln!(
+                    out,
+                    "{}. {} at {}",
+                    i + 1,
+                    step.rule_name,
+                    show_paths(&step.paths)
+                )
+                .unwrap();
+                writeln!(out, "   {}", show(before)).unwrap();
+                writeln!(out, "   => {}", show(&step.expr)).unwrap();
+            }
+            writeln!(out, "result: {}", show(result)).unwrap();
+        }
+        Format::Markdown => {
+            let code = |text: &str| format!("`{}`", text.replace('|', "\\|"));
+            writeln!(out, "Start: {}", code(&show(start))).unwrap();
+            writeln!(out).unwrap();
+            writeln!(out, "| # | Rule | Position | Before | After |").unwrap();
+            writeln!(out, "|---|------|----------|--------|-------|").unwrap();
+            for (i, (step, before)) in steps.iter().zip(befores).enumerate() {
+                writeln!(
+                    out,
+                    "| {} | {} | {} | {} | {} |",
+                    i + 1,
+                    code(&step.rule_name),
+                    code(&show_paths(&step.paths)),
+                    code(&show(before)),
+                    code(&show(&step.expr))
+                )
+                .unwrap();
+            }
+            writeln!(out).unwrap();
+            writeln!(out, "Result: {}", code(&show(result))).unwrap();
+        }
+        Format::Json => {
+            writeln!(out, "{{").unwrap();
+            writeln!(out, "  \"start\": {},", json_string(&show(start))).unwrap();
+            writeln!(out, "  \"steps\": [").unwrap();
+            for (i, (step, before)) in steps.iter().zip(befores).enumerate() {
+                let paths: Vec<String> = step
+                    .paths
+                    .iter()
+                    .map(|path| format!("{:?}", path))
+                    .collect();
+                write!(
+                    out,
+                    "    {{\"rule\": {}, \"paths\": [{}], \"before\": {}, \"after\": {}}}",
+                    json_string(&step.rule_name),
+                    paths.join(", "),
+                    json_string(&show(before)),
+                    json_string(&show(&step.expr))
+                )
+                .unwrap();
+                writeln!(out, "{}", if i + 1 < steps.len() { "," } else { "" }).unwrap();
+            }
+            writeln!(out, "  ],").unwrap();
+            writeln!(out, "  \"result\": {}", json_string(&show(result))).unwrap();
+            writeln!(out, "}}").unwrap();
+        }
+    }
+    out
+}
+
+fn show_paths(paths: &[Path]) -> String {
+    let paths: Vec<String> = paths.iter().map(|path| format!("{:?}", path)).collect();
+    paths.join(", ")
+}
+
+fn json_string(text: &str) -> String {
+    let mut out = String::from("\"");
+    for x in text.chars() {
+        match x {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            x if (x as u32) < 0x20 => write!(out, "\\u{:04x}", x as u32).unwrap(),
+            x => out.push(x),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+fn swap_twice() -> (Expr, Vec<Step>) {
+    let parse = |source: &str| source.parse::<Expr>().unwrap();
+    let step = |paths: Vec<Path>, expr: &str| Step {
+        rule_name: "swap".to_string(),
+        paths,
+        expr: parse(expr),
+    };
+    (
+        parse("swap(swap(pair(a, b)))"),
+        vec![
+            step(vec![vec![0]], "swap(pair(b, a))"),
+            step(vec![vec![]], "pair(a, b)"),
+        ],
+    )
+}
+
+#[test]
+fn export_text_and_markdown() {
+    let (start, steps) = swap_twice();
+    let operators = Operators::default();
+    assert_eq!(
+        export(&start, &steps, &operators, Format::Text),
+        "start: swap(swap(pair(a, b)))
+1. swap at [0]
+   swap(swap(pair(a, b)))
+   => swap(pair(b, a))
+2. swap at []
+   swap(pair(b, a))
+   => pair(a, b)
+result: pair(a, b)
+"
+    );
+    assert_eq!(
+        export(&start, &steps, &operators, Format::Markdown),
+        "Start: `swap(swap(pair(a, b)))`
+
+| # | Rule | Position | Before | After |
+|---|------|----------|--------|-------|
+| 1 | `swap` | `[0]` | `swap(swap(pair(a, b)))` | `swap(pair(b, a))` |
+| 2 | `swap` | `[]` | `swap(pair(b, a))` | `pair(a, b)` |
+
+Result: `pair(a, b)`
+"
+    );
+}
+
+#[test]
+fn export_json() {
+    let (start, steps) = swap_twice();
+    assert_eq!(
+        export(&start, &steps, &Operators::default(), Format::Json),
+        r#"{
+  "start": "swap(swap(pair(a, b)))",
+  "steps": [
+    {"rule": "swap", "paths": [[0]], "before": "swap(swap(pair(a, b)))", "after": "swap(pair(b, a))"},
+    {"rule": "swap", "paths": [[]], "before": "swap(pair(b, a))", "after": "pair(a, b)"}
+  ],
+  "result": "pair(a, b)"
+}
+"#
+    );
+    assert_eq!(json_string("a \"b\" \\ c"), r#""a \"b\" \\ c""#);
+}
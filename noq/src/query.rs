@@ -0,0 +1,296 @@
+use std::collections::HashMap;
+
+use crate::{
+    is_var_name, substitute_bindings,
+    unify::{rename_vars, unify},
+    Bindings, Expr, NoqError,
+};
+
+/// A Horn clause: `head` holds if every goal of `body` does. A fact is a
+/// clause with an empty body.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Clause {
+    /// What the clause proves.
+    pub head: Expr,
+    /// The goals it needs, proved from left to right.
+    pub body: Vec<Expr>,
+}
+
+impl Clause {
+    /// A clause that always holds.
+    pub fn fact(head: Expr) -> Self {
+        Clause {
+            head,
+            body: Vec::new(),
+        }
+    }
+}
+
+// A point to backtrack to: goals still to prove, each with the number of
+// resolution steps that led to it, and the query variables as bound so far.
+// Bindings are applied as soon as they are found, so goals never mention a
+// bound variable.
+#[derive(Debug, Clone)]
+struct State {
+    goals: Vec<(Expr, usize)>,
+    answer: Vec<(String, Expr)>,
+}
+
+/// The solutions of a query by SLD resolution: the leftmost goal first, trying
+/// clauses in program order and backtracking into the next clause when a
+/// branch fails, as Prolog does. Solutions are found one at a time, as the
+/// iterator is advanced.
+pub struct Solutions<'a> {
+    program: &'a [Clause],
+    max_depth: usize,
+    // Choice points, the last one is tried next.
+    stack: Vec<State>,
+    // Clause variables are renamed apart with a fresh suffix every time the
+    // clause is used.
+    renamings: usize,
+    depth_limited: bool,
+}
+
+/// Solves `goals` with the clauses of `program`, giving up on any branch
+/// more than `max_depth` resolution steps deep.
+pub fn solve<'a>(program: &'a [Clause], goals: &[Expr], max_depth: usize) -> Solutions<'a> {
+    let mut vars = Vec::new();
+    for goal in goals {
+        for var in goal.vars() {
+            if !vars.contains(&var) {
+                vars.push(var);
+            }
+        }
+    }
+    let answer = vars
+        .into_iter()
+        .map(|var| (var.clone(), Expr::Var(var)))
+        .collect();
+    Solutions {
+        program,
+        max_depth,
+        stack: vec![State {
+            goals: goals.iter().map(|goal| (goal.clone(), 0)).collect(),
+            answer,
+        }],
+        renamings: 0,
+        depth_limited: false,
+    }
+}
+
+impl Solutions<'_> {
+    /// Whether some branch was cut off at the depth limit, in which case
+    /// there may be solutions that were not found.
+    pub fn depth_limited(&self) -> bool {
+        self.depth_limited
+    }
+
+    // Pushes the states that resolving the first goal of `state` with each
+    // clause leads to, so that the first clause is on top.
+    fn expand(&mut self, mut state: State) {
+        let (goal, depth) = state.goals.remove(0);
+        if depth >= self.max_depth {
+            self.depth_limited = true;
+            return;
+        }
+        let mut states = Vec::new();
+        for clause in self.program {
+            self.renamings += 1;
+            // Not a character the lexer accepts, so it cannot clash with the
+            // variables of the query.
+            let suffix = format!("'{}", self.renamings);
+            let head = rename_vars(&clause.head, &suffix);
+            let Some(bindings) = unify(&goal, &head) else {
+                continue;
+            };
+            let resolve = || -> Result<State, NoqError> {
+                let substitute = |expr: &Expr| substitute_bindings(&bindings, expr);
+                let mut goals = Vec::new();
+                for body_goal in &clause.body {
+                    goals.push((substitute(&rename_vars(body_goal, &suffix))?, depth + 1));
+                }
+                for (rest_goal, rest_depth) in &state.goals {
+                    goals.push((substitute(rest_goal)?, *rest_depth));
+                }
+                let mut answer = Vec::new();
+                for (var, value) in &state.answer {
+                    answer.push((var.clone(), substitute(value)?));
+                }
+                Ok(State { goals, answer })
+            };
+            // A functor variable the unifier binds to something other than a
+            // symbol elsewhere in the clause or the other goals is a clash
+            // too, which only fails this branch.
+            if let Ok(state) = resolve() {
+                states.push(state);
+            }
+        }
+        self.stack.extend(states.into_iter().rev());
+    }
+}
+
+impl Iterator for Solutions<'_> {
+    /// The query variables with their values.
+    type Item = Bindings;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(state) = self.stack.pop() {
+            if state.goals.is_empty() {
+                return Some(name_answer(state.answer));
+            }
+            self.expand(state);
+        }
+        None
+    }
+}
+
+// Gives the variables that renaming clauses apart introduced names the user
+// can type: a query variable bound to one of them lends it its name, the rest
+// get fresh names `_1`, `_2` and so on that the query does not use.
+fn name_answer(answer: Vec<(String, Expr)>) -> Bindings {
+    let mut names = HashMap::new();
+    for (var, value) in &answer {
+        if let Expr::Var(name) = value {
+            if name.contains('\'') && !names.contains_key(name) {
+                names.insert(name.clone(), var.clone());
+            }
+        }
+    }
+    let mut fresh = 0;
+    for (_, value) in &answer {
+        for name in value.vars() {
+            if !name.contains('\'') || names.contains_key(&name) {
+                continue;
+            }
+            let new_name = loop {
+                fresh += 1;
+                let new_name = format!("_{}", fresh);
+                if answer.iter().all(|(var, _)| *var != new_name) {
+                    break new_name;
+                }
+            };
+            names.insert(name, new_name);
+        }
+    }
+    answer
+        .into_iter()
+        .map(|(var, value)| (var, rename(&value, &names)))
+        .collect()
+}
+
+fn rename(expr: &Expr, names: &HashMap<String, String>) -> Expr {
+    let name_of = |name: &String| names.get(name).unwrap_or(name).clone();
+    match expr {
+        Expr::Var(name) => Expr::Var(name_of(name)),
+        Expr::Sym(_) | Expr::Num(_) => expr.clone(),
+        Expr::Fun(name, args) => Expr::Fun(
+            match is_var_name(name) {
+                true => name_of(name),
+                false => name.clone(),
+            },
+            args.iter().map(|arg| rename(arg, names)).collect(),
+        ),
+    }
+}
+
+#[cfg(test)]
+fn family() -> Vec<Clause> {
+    let parse = |source: &str| source.parse::<Expr>().unwrap();
+    let clause = |head: &str, body: &[&str]| Clause {
+        head: parse(head),
+        body: body.iter().map(|goal| parse(goal)).collect(),
+    };
+    vec![
+        Clause::fact(parse("parent(alice, bob)")),
+        Clause::fact(parse("parent(bob, carol)")),
+        Clause::fact(parse("parent(bob, dave)")),
+        clause("ancestor(X, Y)", &["parent(X, Y)"]),
+        clause("ancestor(X, Z)", &["parent(X, Y)", "ancestor(Y, Z)"]),
+    ]
+}
+
+#[cfg(test)]
+fn answers(solutions: Solutions, var: &str) -> Vec<String> {
+    solutions
+        .map(|bindings| bindings[var].to_string())
+        .collect()
+}
+
+#[test]
+fn solve_queries_with_backtracking() {
+    let program = family();
+    let query = |source: &str| solve(&program, &[source.parse().unwrap()], 100);
+    assert_eq!(answers(query("parent(X, bob)"), "X"), ["alice"]);
+    assert_eq!(
+        answers(query("ancestor(alice, Y)"), "Y"),
+        ["bob", "carol", "dave"]
+    );
+    assert_eq!(query("parent(carol, X)").count(), 0);
+    // A ground query has a single empty solution if it holds.
+    let solutions: Vec<Bindings> = query("ancestor(alice, dave)").collect();
+    assert_eq!(solutions, [Bindings::new()]);
+
+    let goals = [
+        "parent(X, Y)".parse().unwrap(),
+        "parent(Y, dave)".parse().unwrap(),
+    ];
+    assert_eq!(answers(solve(&program, &goals, 100), "X"), ["alice"]);
+
+    // A clause whose head would bind a functor variable to something other
+    // than a symbol does not apply, and the search goes on.
+    let program = vec![
+        Clause::fact("q(g(b), Y)".parse().unwrap()),
+        Clause::fact("q(c, d)".parse().unwrap()),
+        Clause::fact("q(h, h(a))".parse().unwrap()),
+    ];
+    let query = solve(&program, &["q(X, X(a))".parse().unwrap()], 100);
+    assert_eq!(answers(query, "X"), ["h"]);
+}
+
+#[test]
+fn solutions_are_lazy_and_depth_limited() {
+    let parse = |source: &str| source.parse::<Expr>().unwrap();
+    let program = vec![
+        Clause::fact(parse("nat(zero)")),
+        Clause {
+            head: parse("nat(succ(X))"),
+            body: vec![parse("nat(X)")],
+        },
+    ];
+    // Infinitely many solutions, only as many as asked for are computed.
+    let first: Vec<String> = solve(&program, &[parse("nat(X)")], usize::MAX)
+        .take(3)
+        .map(|bindings| bindings["X"].to_string())
+        .collect();
+    assert_eq!(first, ["zero", "succ(zero)", "succ(succ(zero))"]);
+
+    let mut solutions = solve(&program, &[parse("nat(X)")], 2);
+    assert_eq!(solutions.by_ref().count(), 2);
+    assert!(solutions.depth_limited());
+    let mut solutions = solve(&program, &[parse("nat(succ(zero))")], 10);
+    assert_eq!(solutions.by_ref().count(), 1);
+    assert!(!solutions.depth_limited());
+}
+
+#[test]
+fn answers_name_variables_like_the_query() {
+    let parse = |source: &str| source.parse::<Expr>().unwrap();
+    let program = vec![
+        Clause::fact(parse("same(X, X)")),
+        Clause::fact(parse("pair(f(X), Y)")),
+    ];
+    let answer = |goal: &str| {
+        let bindings = solve(&program, &[parse(goal)], 10).next().unwrap();
+        let mut shown: Vec<String> = bindings
+            .iter()
+            .map(|(var, value)| format!("{} = {}", var, value))
+            .collect();
+        shown.sort();
+        shown.join(", ")
+    };
+    assert_eq!(answer("same(A, B)"), "A = A, B = A");
+    assert_eq!(answer("same(A, f(B))"), "A = f(B), B = B");
+    // Variables no query variable stands for get fresh names.
+    assert_eq!(answer("pair(A, B)"), "A = f(_1), B = B");
+    assert_eq!(answer("pair(_1, B)"), "B = B, _1 = f(_2)");
+}
@@ -0,0 +1,196 @@
+use std::fmt::Write;
+
+use crate::{
+    theory::{first_match, Theories},
+    Bindings, Expr, NoqError, Operators, Path, Rule,
+};
+
+/// A subexpression a pattern matched, with the bindings of the match.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Match {
+    /// Where the subexpression is.
+    pub path: Path,
+    /// What the variables of the pattern are bound to.
+    pub bindings: Bindings,
+}
+
+/// Where `rule` rewrites `expr` modulo `theories`, in the order of
+/// `Rule::match_paths`, each with the first bindings that satisfy its guards.
+/// A head that only matches some of the arguments of an AC application has
+/// no bindings for the whole subexpression, so it gets none.
+pub fn rule_matches(rule: &Rule, theories: &Theories, expr: &Expr) -> Result<Vec<Match>, NoqError> {
+    let mut matches = Vec::new();
+    for path in rule.match_paths(theories, expr)? {
+        let subexpr = expr.at(&path).expect("match paths are valid");
+        let bindings = first_match(rule, &rule.head, subexpr, theories)?.unwrap_or_default();
+        matches.push(Match { path, bindings });
+    }
+    Ok(matches)
+}
+
+/// The expression as an indented tree, one subexpression per line, for
+/// reading deeply nested expressions in the terminal.
+pub fn tree(expr: &Expr, operators: &Operators) -> String {
+    let mut out = String::new();
+    writeln!(out, "{}", label(expr, operators)).unwrap();
+    tree_children(expr, operators, "", &mut out);
+    out
+}
+
+fn tree_children(expr: &Expr, operators: &Operators, indent: &str, out: &mut String) {
+    let Expr::Fun(_, args) = expr else {
+        return;
+    };
+    for (i, arg) in args.iter().enumerate() {
+        let last = i + 1 == args.len();
+        let (branch, next_indent) = if last {
+            ("`-- ", "    ")
+        } else {
+            ("|-- ", "|   ")
+        };
+        writeln!(out, "{}{}{}", indent, branch, label(arg, operators)).unwrap();
+        tree_children(arg, operators, &format!("{}{}", indent, next_indent), out);
+    }
+}
+
+/// The expression as a Graphviz graph, with an edge from every functor to
+/// each of its arguments in order. The subexpressions in `matches` are
+/// filled, and every binding of a match gets a note pointing at the
+/// occurrences of its value inside the matched subexpression. A value that
+/// does not occur as is, like the part of an AC application a variable took,
+/// is only shown in its note.
+pub fn dot(expr: &Expr, operators: &Operators, matches: &[Match]) -> String {
+    let mut out = String::new();
+    writeln!(out, "digraph {{").unwrap();
+    writeln!(out, "  ordering=out;").unwrap();
+    writeln!(out, "  node [shape=box, fontname=monospace];").unwrap();
+    for path in expr.paths() {
+        let subexpr = expr.at(&path).expect("paths of expr are valid");
+        writeln!(
+            out,
+            "  {} [label={}];",
+            node_id(&path),
+            dot_string(&label(subexpr, operators))
+        )
+        .unwrap();
+        if let Expr::Fun(_, args) = subexpr {
+            for i in 0..args.len() {
+                let mut arg_path = path.clone();
+                arg_path.push(i);
+                writeln!(out, "  {} -> {};", node_id(&path), node_id(&arg_path)).unwrap();
+            }
+        }
+    }
+    for (i, m) in matches.iter().enumerate() {
+        let Some(subexpr) = expr.at(&m.path) else {
+            continue;
+        };
+        writeln!(
+            out,
+            "  {} [style=filled, fillcolor=lightblue];",
+            node_id(&m.path)
+        )
+        .unwrap();
+        let mut bindings: Vec<_> = m.bindings.iter().collect();
+        bindings.sort();
+        for (j, (var, value)) in bindings.into_iter().enumerate() {
+            let note = format!("m{}_{}", i, j);
+            let text = format!("{} = {}", var, operators.show(value));
+            writeln!(
+                out,
+                "  {} [shape=note, style=filled, fillcolor=lightyellow, label={}];",
+                note,
+                dot_string(&text)
+            )
+            .unwrap();
+            for occurrence in occurrences(subexpr, value) {
+                let mut path = m.path.clone();
+                path.extend(occurrence);
+                writeln!(
+                    out,
+                    "  {} [style=filled, fillcolor=lightyellow];",
+                    node_id(&path)
+                )
+                .unwrap();
+                writeln!(out, "  {} -> {} [style=dashed];", note, node_id(&path)).unwrap();
+            }
+        }
+    }
+    writeln!(out, "}}").unwrap();
+    out
+}
+
+// Paths to the outermost subexpressions of `expr` equal to `value`.
+fn occurrences(expr: &Expr, value: &Expr) -> Vec<Path> {
+    if expr == value {
+        return vec![Path::new()];
+    }
+    let mut paths = Vec::new();
+    if let Expr::Fun(_, args) = expr {
+        for (i, arg) in args.iter().enumerate() {
+            for mut path in occurrences(arg, value) {
+                path.insert(0, i);
+                paths.push(path);
+            }
+        }
+    }
+    paths
+}
+
+// Functors are labelled with their operator symbol if they have one, and only
+// their name, the arguments are nodes of their own.
+fn label(expr: &Expr, operators: &Operators) -> String {
+    match expr {
+        Expr::Fun(name, args) => match operators.by_name(name) {
+            Some(op) if args.len() == 2 => op.symbol.clone(),
+            _ => name.clone(),
+        },
+        _ => operators.show(expr).to_string(),
+    }
+}
+
+fn node_id(path: &[usize]) -> String {
+    let mut id = String::from("n");
+    for i in path {
+        write!(id, "_{}", i).unwrap();
+    }
+    id
+}
+
+fn dot_string(text: &str) -> String {
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[test]
+fn render_tree() {
+    let expr: Expr = "f(a + b * 2, g(X))".parse().unwrap();
+    assert_eq!(
+        tree(&expr, &Operators::default()),
+        "f\n\
+         |-- +\n\
+         |   |-- a\n\
+         |   `-- *\n\
+         |       |-- b\n\
+         |       `-- 2\n\
+         `-- g\n    \
+             `-- X\n"
+    );
+}
+
+#[test]
+fn render_dot_with_matches() {
+    let operators = Operators::default();
+    let expr: Expr = "f(g(a), b)".parse().unwrap();
+    let rule: Rule = "g(X) = X".parse().unwrap();
+    let matches = rule_matches(&rule, &Theories::new(), &expr).unwrap();
+    assert_eq!(matches.len(), 1);
+    let graph = dot(&expr, &operators, &matches);
+    assert!(graph.starts_with("digraph {\n"));
+    assert!(graph.contains("  n [label=\"f\"];\n"));
+    assert!(graph.contains("  n -> n_0;\n  n -> n_1;\n"));
+    assert!(graph.contains("  n_0 [style=filled, fillcolor=lightblue];\n"));
+    assert!(graph
+        .contains("  m0_0 [shape=note, style=filled, fillcolor=lightyellow, label=\"X = a\"];\n"));
+    assert!(graph.contains("  m0_0 -> n_0_0 [style=dashed];\n"));
+    assert!(!graph.contains("n_1 [style=filled"));
+}
@@ -0,0 +1,316 @@
+use crate::{
+    theory::{self, Theories},
+    Expr, NoqError, Path, Rule, Step,
+};
+
+// How many times `repeat` runs a tactic that keeps changing the expression
+// before giving up.
+const MAX_REPEATS: usize = 1000;
+
+/// A way of rewriting an expression built out of rules, which either succeeds
+/// with a new expression or fails.
+#[derive(Debug, Clone)]
+pub enum Tactic {
+    /// The named rule, at the root only.
+    Rule(String, Rule),
+    /// The first tactic and then the second on its result, failing if either
+    /// does.
+    Then(Box<Tactic>, Box<Tactic>),
+    /// The first tactic, or the second if the first fails.
+    OrElse(Box<Tactic>, Box<Tactic>),
+    /// The tactic as long as it succeeds and changes the expression. Never
+    /// fails.
+    Repeat(Box<Tactic>),
+    /// The tactic, or nothing if it fails. Never fails.
+    Try(Box<Tactic>),
+    /// The tactic on the subexpression at the path.
+    At(Path, Box<Tactic>),
+    /// The tactic on every subexpression, parents before children, without
+    /// visiting what it rewrote. Fails if it fails everywhere.
+    Everywhere(Box<Tactic>),
+}
+
+impl Rule {
+    /// The rule as a tactic, applied at the root.
+    pub fn tactic(&self, name: &str) -> Tactic {
+        Tactic::Rule(name.to_string(), self.clone())
+    }
+}
+
+impl Tactic {
+    /// `then(self, next)` in scripts.
+    pub fn then(self, next: Tactic) -> Tactic {
+        Tactic::Then(Box::new(self), Box::new(next))
+    }
+
+    /// `orelse(self, other)` in scripts.
+    pub fn or_else(self, other: Tactic) -> Tactic {
+        Tactic::OrElse(Box::new(self), Box::new(other))
+    }
+
+    /// `repeat(self)` in scripts.
+    pub fn repeat(self) -> Tactic {
+        Tactic::Repeat(Box::new(self))
+    }
+
+    /// `try` in scripts, which is a keyword in Rust.
+    pub fn attempt(self) -> Tactic {
+        Tactic::Try(Box::new(self))
+    }
+
+    /// `at(path, self)` in scripts.
+    pub fn at(self, path: Path) -> Tactic {
+        Tactic::At(path, Box::new(self))
+    }
+
+    /// `everywhere(self)` in scripts.
+    pub fn everywhere(self) -> Tactic {
+        Tactic::Everywhere(Box::new(self))
+    }
+
+    /// The result of the tactic on `expr` with every rule applied on the way,
+    /// or None if it failed. Rules match modulo `theories`.
+    pub fn apply(
+        &self,
+        theories: &Theories,
+        expr: &Expr,
+    ) -> Result<Option<(Expr, Vec<Step>)>, NoqError> {
+        let mut steps = Vec::new();
+        Ok(self
+            .run(theories, expr, &mut Vec::new(), &mut steps)?
+            .map(|expr| (expr, steps)))
+    }
+
+    // Runs the tactic on the subexpression of `expr` at `focus`, returning
+    // the whole new expression. Steps of a tactic that failed are taken back.
+    fn run(
+        &self,
+        theories: &Theories,
+        expr: &Expr,
+        focus: &mut Path,
+        steps: &mut Vec<Step>,
+    ) -> Result<Option<Expr>, NoqError> {
+        let depth = steps.len();
+        let result = self.run_impl(theories, expr, focus, steps)?;
+        if result.is_none() {
+            steps.truncate(depth);
+        }
+        Ok(result)
+    }
+
+    fn run_impl(
+        &self,
+        theories: &Theories,
+        expr: &Expr,
+        focus: &mut Path,
+        steps: &mut Vec<Step>,
+    ) -> Result<Option<Expr>, NoqError> {
+        match self {
+            Tactic::Rule(name, rule) => {
+                let Some(subexpr) = expr.at(focus) else {
+                    return Ok(None);
+                };
+                let Some(new_subexpr) = theory::rewrite(rule, subexpr, theories)? else {
+                    return Ok(None);
+                };
+                let new_expr = expr
+                    .replace_at(focus, new_subexpr)
+                    .expect("focus is a valid path");
+                steps.push(Step {
+                    rule_name: name.clone(),
+                    paths: vec![focus.clone()],
+                    expr: new_expr.clone(),
+                });
+                Ok(Some(new_expr))
+            }
+            Tactic::Then(first, second) => match first.run(theories, expr, focus, steps)? {
+                Some(expr) => second.run(theories, &expr, focus, steps),
+                None => Ok(None),
+            },
+            Tactic::OrElse(first, second) => match first.run(theories, expr, focus, steps)? {
+                Some(expr) => Ok(Some(expr)),
+                None => second.run(theories, expr, focus, steps),
+            },
+            Tactic::Repeat(tactic) => {
+                let mut current = expr.clone();
+                for _ in 0..MAX_REPEATS {
+                    match tactic.run(theories, &current, focus, steps)? {
+                        Some(next) if next != current => current = next,
+                        _ => return Ok(Some(current)),
+                    }
+                }
+                Err(NoqError::RepeatLimit {
+                    loc: None,
+                    limit: MAX_REPEATS,
+                })
+            }
+            Tactic::Try(tactic) => Ok(Some(
+                tactic
+                    .run(theories, expr, focus, steps)?
+                    .unwrap_or_else(|| expr.clone()),
+            )),
+            Tactic::At(path, tactic) => {
+                let depth = focus.len();
+                focus.extend(path);
+                let result = tactic.run(theories, expr, focus, steps);
+                focus.truncate(depth);
+                result
+            }
+            Tactic::Everywhere(tactic) => {
+                if let Some(new_expr) = tactic.run(theories, expr, focus, steps)? {
+                    return Ok(Some(new_expr));
+                }
+                let arity = match expr.at(focus) {
+                    Some(Expr::Fun(_, args)) => args.len(),
+                    _ => 0,
+                };
+                let mut current = None;
+                for i in 0..arity {
+                    focus.push(i);
+                    let result = self.run(theories, current.as_ref().unwrap_or(expr), focus, steps);
+                    focus.pop();
+                    if let Some(new_expr) = result? {
+                        current = Some(new_expr);
+                    }
+                }
+                Ok(current)
+            }
+        }
+    }
+}
+
+/// ```text
+/// tactic := name
+///         | ('then' | 'orelse') '(' tactic (',' tactic)+ ')'
+///         | ('repeat' | 'try' | 'everywhere') '(' tactic ')'
+///         | 'at' '(' index (',' index)* ',' tactic ')'
+/// ```
+///
+/// Tactics are written as expressions and read back from them. `lookup` gives
+/// the tactic a name stands for, a rule or a tactic defined earlier.
+pub fn from_expr(expr: &Expr, lookup: &dyn Fn(&str) -> Option<Tactic>) -> Result<Tactic, String> {
+    let from_expr = |expr| from_expr(expr, lookup);
+    match expr {
+        Expr::Sym(name) | Expr::Var(name) => {
+            lookup(name).ok_or_else(|| format!("unknown rule or tactic `{}`", name))
+        }
+        Expr::Fun(name, args) => match (name.as_str(), args.as_slice()) {
+            ("then" | "orelse", [first, rest @ ..]) if !rest.is_empty() => {
+                let mut tactic = from_expr(first)?;
+                for arg in rest {
+                    let next = from_expr(arg)?;
+                    tactic = match name.as_str() {
+                        "then" => tactic.then(next),
+                        _ => tactic.or_else(next),
+                    };
+                }
+                Ok(tactic)
+            }
+            ("repeat", [arg]) => Ok(from_expr(arg)?.repeat()),
+            ("try", [arg]) => Ok(from_expr(arg)?.attempt()),
+            ("everywhere", [arg]) => Ok(from_expr(arg)?.everywhere()),
+            ("at", [indices @ .., arg]) => {
+                let mut path = Path::new();
+                for index in indices {
+                    let index = match index {
+                        Expr::Num(number) => number
+                            .as_integer()
+                            .and_then(|index| usize::try_from(index).ok()),
+                        _ => None,
+                    };
+                    path.push(index.ok_or_else(|| format!("expected argument index in {}", expr))?);
+                }
+                Ok(from_expr(arg)?.at(path))
+            }
+            _ => Err(format!("expected tactic but got {}", expr)),
+        },
+        Expr::Num(_) => Err(format!("expected tactic but got {}", expr)),
+    }
+}
+
+#[cfg(test)]
+fn run_tactic(tactic: &Tactic, expr: &str) -> Option<(String, Vec<String>)> {
+    tactic
+        .apply(&Theories::new(), &expr.parse().unwrap())
+        .unwrap()
+        .map(|(expr, steps)| {
+            let rule_names = steps.into_iter().map(|step| step.rule_name).collect();
+            (expr.to_string(), rule_names)
+        })
+}
+
+#[test]
+fn tactic_combinators() {
+    let rule = |name: &str, source: &str| source.parse::<Rule>().unwrap().tactic(name);
+    let zero = rule("zero", "X + 0 = X");
+    let swap = rule("swap", "X + Y = Y + X");
+
+    assert_eq!(run_tactic(&zero, "a + 0").unwrap().0, "a");
+    assert_eq!(run_tactic(&zero, "0 + a"), None);
+    assert_eq!(
+        run_tactic(&swap.clone().then(zero.clone()), "0 + a").unwrap(),
+        (
+            "a".to_string(),
+            vec!["swap".to_string(), "zero".to_string()]
+        )
+    );
+    // A failed `then` takes back the steps of its first half too.
+    assert_eq!(
+        run_tactic(&swap.clone().then(swap.clone().then(zero.clone())), "0 + a"),
+        None
+    );
+    assert_eq!(
+        run_tactic(&zero.clone().or_else(swap.clone()), "0 + a")
+            .unwrap()
+            .0,
+        "a + 0"
+    );
+    assert_eq!(run_tactic(&zero.clone().attempt(), "b").unwrap().0, "b");
+    assert_eq!(
+        run_tactic(&zero.clone().repeat(), "a + 0 + 0 + 0")
+            .unwrap()
+            .1
+            .len(),
+        3
+    );
+    assert_eq!(
+        run_tactic(&zero.clone().at(vec![1]), "f(a, b + 0)")
+            .unwrap()
+            .0,
+        "f(a, b)"
+    );
+    assert_eq!(run_tactic(&zero.clone().at(vec![2]), "f(a, b + 0)"), None);
+    assert_eq!(
+        run_tactic(&zero.clone().everywhere(), "f(a + 0, g(b + 0 + 0))")
+            .unwrap()
+            .0,
+        "f(a, g(b + 0))"
+    );
+
+    let error = swap
+        .repeat()
+        .apply(&Theories::new(), &"a + b".parse().unwrap())
+        .unwrap_err();
+    assert_eq!(error.to_string(), "repeat did not stop after 1000 rounds");
+}
+
+#[test]
+fn tactics_from_expressions() {
+    let zero: Rule = "X + 0 = X".parse().unwrap();
+    let lookup = |name: &str| (name == "zero").then(|| zero.tactic(name));
+    let tactic = |source: &str| from_expr(&source.parse().unwrap(), &lookup);
+    let simplify = tactic("then(try(zero), everywhere(zero), at(1, 0, repeat(zero)))").unwrap();
+    assert_eq!(
+        run_tactic(&simplify, "f(a + 0, g(b + 0 + 0))").unwrap().0,
+        "f(a, g(b))"
+    );
+    assert_eq!(tactic("swap").unwrap_err(), "unknown rule or tactic `swap`");
+    assert_eq!(
+        tactic("at(a, zero)").unwrap_err(),
+        "expected argument index in at(a, zero)"
+    );
+    assert_eq!(
+        tactic("repeat(zero, zero)").unwrap_err(),
+        "expected tactic but got repeat(zero, zero)"
+    );
+}
@@ -0,0 +1,464 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ops::ControlFlow,
+};
+
+use crate::{is_var_name, substitute_bindings, Bindings, Expr, NoqError, Rule};
+
+/// The equations a functor is declared to satisfy with `ac` or `comm`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Theory {
+    /// f(X, Y) = f(Y, X)
+    C,
+    /// C, and f(f(X, Y), Z) = f(X, f(Y, Z))
+    AC,
+}
+
+/// Functors declared to satisfy an equational theory, matched modulo it.
+pub type Theories = HashMap<String, Theory>;
+
+/// Every way of matching `pattern` against `value` modulo `theories`, without
+/// duplicates and always in the same order, so picking the first one is
+/// deterministic. Without theories there is at most one.
+///
+/// A variable under an AC functor can take several arguments of it, bound as
+/// a right-nested application of the functor in the order they appear in
+/// `value`. Functor variables only match syntactically.
+///
+/// There can be exponentially many matches modulo AC, so rewriting goes
+/// through `first_match`, which stops at the first one it can use.
+pub fn pattern_match(pattern: &Expr, value: &Expr, theories: &Theories) -> Vec<Bindings> {
+    let mut results = Vec::new();
+    let mut seen = HashSet::new();
+    let flow: ControlFlow<()> = solve(
+        vec![(pattern.clone(), value.clone())],
+        Bindings::new(),
+        theories,
+        &mut |bindings| {
+            let mut key: Vec<(String, Expr)> = bindings.clone().into_iter().collect();
+            key.sort();
+            if seen.insert(key) {
+                results.push(bindings);
+            }
+            ControlFlow::Continue(())
+        },
+    );
+    debug_assert!(flow.is_continue());
+    results
+}
+
+// `goals` are popped from the end, so they are solved last to first. Every
+// solution is passed to `found`, until it breaks.
+fn solve<B>(
+    mut goals: Vec<(Expr, Expr)>,
+    mut bindings: Bindings,
+    theories: &Theories,
+    found: &mut dyn FnMut(Bindings) -> ControlFlow<B>,
+) -> ControlFlow<B> {
+    use Expr::*;
+    let Some((pattern, value)) = goals.pop() else {
+        return found(bindings);
+    };
+    match (&pattern, &value) {
+        (Var(name), _) => {
+            match bindings.get(name) {
+                Some(bound_value) if !equal_modulo(bound_value, &value, theories) => {
+                    return ControlFlow::Continue(())
+                }
+                Some(_) => {}
+                None => {
+                    bindings.insert(name.clone(), value.clone());
+                }
+            }
+            solve(goals, bindings, theories, found)
+        }
+        (Sym(name1), Sym(name2)) if name1 == name2 => solve(goals, bindings, theories, found),
+        (Num(a), Num(b)) if a == b => solve(goals, bindings, theories, found),
+        (Fun(name1, args1), Fun(name2, args2)) if is_var_name(name1) => {
+            if args1.len() != args2.len() {
+                return ControlFlow::Continue(());
+            }
+            let functor = Sym(name2.clone());
+            match bindings.get(name1) {
+                Some(bound_value) if *bound_value != functor => return ControlFlow::Continue(()),
+                Some(_) => {}
+                None => {
+                    bindings.insert(name1.clone(), functor);
+                }
+            }
+            push_args(&mut goals, args1, args2);
+            solve(goals, bindings, theories, found)
+        }
+        (Fun(name1, args1), Fun(name2, args2)) if name1 == name2 => match theories.get(name1) {
+            Some(Theory::C) if args1.len() == 2 && args2.len() == 2 => {
+                for swapped in [args2.clone(), vec![args2[1].clone(), args2[0].clone()]] {
+                    let mut goals = goals.clone();
+                    push_args(&mut goals, args1, &swapped);
+                    solve(goals, bindings.clone(), theories, found)?;
+                }
+                ControlFlow::Continue(())
+            }
+            Some(Theory::AC) if args1.len() == 2 && args2.len() == 2 => {
+                let pattern_args = flatten(name1, args1);
+                let value_args = flatten(name2, args2);
+                match_ac(
+                    name1,
+                    &pattern_args,
+                    &value_args,
+                    &goals,
+                    &bindings,
+                    theories,
+                    found,
+                )
+            }
+            _ => {
+                if args1.len() != args2.len() {
+                    return ControlFlow::Continue(());
+                }
+                push_args(&mut goals, args1, args2);
+                solve(goals, bindings, theories, found)
+            }
+        },
+        _ => ControlFlow::Continue(()),
+    }
+}
+
+/// Rewrites `value` at the root with `rule`, using the first match that
+/// satisfies its guards. A head that is an application of an AC functor can
+/// also match just some of the arguments of `value`, the rest are kept next to
+/// the result, so `X + neg(X) = zero` rewrites `a + b + neg(a)` to `zero + b`.
+pub fn rewrite(rule: &Rule, value: &Expr, theories: &Theories) -> Result<Option<Expr>, NoqError> {
+    if let Some(bindings) = first_match(rule, &rule.head, value, theories)? {
+        return substitute_bindings(&bindings, &rule.body).map(Some);
+    }
+    let Expr::Fun(name, args) = &rule.head else {
+        return Ok(None);
+    };
+    if args.len() != 2 || theories.get(name) != Some(&Theory::AC) {
+        return Ok(None);
+    }
+    // Not a name the lexer can produce, so it cannot clash with the variables
+    // of the rule.
+    let rest = "Rest'".to_string();
+    let extended = Expr::Fun(
+        name.clone(),
+        vec![rule.head.clone(), Expr::Var(rest.clone())],
+    );
+    let Some(bindings) = first_match(rule, &extended, value, theories)? else {
+        return Ok(None);
+    };
+    Ok(Some(Expr::Fun(
+        name.clone(),
+        vec![
+            substitute_bindings(&bindings, &rule.body)?,
+            bindings[&rest].clone(),
+        ],
+    )))
+}
+
+/// The first match of `head` against `value` that satisfies the guards of
+/// `rule`, in the order of `pattern_match`. Matches after it are never tried.
+pub(crate) fn first_match(
+    rule: &Rule,
+    head: &Expr,
+    value: &Expr,
+    theories: &Theories,
+) -> Result<Option<Bindings>, NoqError> {
+    let flow = solve(
+        vec![(head.clone(), value.clone())],
+        Bindings::new(),
+        theories,
+        &mut |bindings| match satisfies_guards(rule, &bindings, theories) {
+            Ok(true) => ControlFlow::Break(Ok(bindings)),
+            Ok(false) => ControlFlow::Continue(()),
+            Err(error) => ControlFlow::Break(Err(error)),
+        },
+    );
+    match flow {
+        ControlFlow::Break(result) => result.map(Some),
+        ControlFlow::Continue(()) => Ok(None),
+    }
+}
+
+/// Whether every guard of `rule` holds with `bindings`.
+pub fn satisfies_guards(
+    rule: &Rule,
+    bindings: &Bindings,
+    theories: &Theories,
+) -> Result<bool, NoqError> {
+    for guard in &rule.guards {
+        if !guard.holds(bindings, theories)? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+// Pushed in reverse so the arguments are solved left to right.
+fn push_args(goals: &mut Vec<(Expr, Expr)>, patterns: &[Expr], values: &[Expr]) {
+    for (pattern, value) in patterns.iter().zip(values).rev() {
+        goals.push((pattern.clone(), value.clone()));
+    }
+}
+
+// Every non-variable pattern argument takes one value argument, then every
+// variable takes at least one of the rest.
+fn match_ac<B>(
+    name: &str,
+    pattern_args: &[Expr],
+    value_args: &[Expr],
+    goals: &[(Expr, Expr)],
+    bindings: &Bindings,
+    theories: &Theories,
+    found: &mut dyn FnMut(Bindings) -> ControlFlow<B>,
+) -> ControlFlow<B> {
+    if pattern_args.len() > value_args.len() {
+        return ControlFlow::Continue(());
+    }
+    let (vars, others): (Vec<&Expr>, Vec<&Expr>) = pattern_args
+        .iter()
+        .partition(|arg| matches!(arg, Expr::Var(_)));
+
+    arrangements(
+        value_args.len(),
+        others.len(),
+        &mut Vec::new(),
+        &mut |taken| {
+            let rest: Vec<&Expr> = (0..value_args.len())
+                .filter(|j| !taken.contains(j))
+                .map(|j| &value_args[j])
+                .collect();
+            partitions(rest.len(), vars.len(), &mut |groups| {
+                let mut goals = goals.to_vec();
+                for (var, group) in vars.iter().zip(groups).rev() {
+                    let group: Vec<Expr> = group.iter().map(|&j| rest[j].clone()).collect();
+                    goals.push(((*var).clone(), nest(name, group)));
+                }
+                for (other, &j) in others.iter().zip(taken).rev() {
+                    goals.push(((*other).clone(), value_args[j].clone()));
+                }
+                solve(goals, bindings.clone(), theories, found)
+            })
+        },
+    )
+}
+
+// Every sequence of `k` distinct indices below `n` that starts with `taken`,
+// in lexicographic order.
+fn arrangements<B>(
+    n: usize,
+    k: usize,
+    taken: &mut Vec<usize>,
+    visit: &mut dyn FnMut(&[usize]) -> ControlFlow<B>,
+) -> ControlFlow<B> {
+    if taken.len() == k {
+        return visit(taken);
+    }
+    for j in 0..n {
+        if !taken.contains(&j) {
+            taken.push(j);
+            let flow = arrangements(n, k, taken, visit);
+            taken.pop();
+            flow?;
+        }
+    }
+    ControlFlow::Continue(())
+}
+
+// Every way of putting `n` items into `k` non-empty groups, keeping the items
+// of a group in order.
+fn partitions<B>(
+    n: usize,
+    k: usize,
+    visit: &mut dyn FnMut(&[Vec<usize>]) -> ControlFlow<B>,
+) -> ControlFlow<B> {
+    if k == 0 {
+        return if n == 0 {
+            visit(&[])
+        } else {
+            ControlFlow::Continue(())
+        };
+    }
+    // The group of every item, counting up like the digits of a number.
+    let mut choice = vec![0; n];
+    loop {
+        let mut groups = vec![Vec::new(); k];
+        for (item, &group) in choice.iter().enumerate() {
+            groups[group].push(item);
+        }
+        if groups.iter().all(|group| !group.is_empty()) {
+            visit(&groups)?;
+        }
+        let Some(i) = choice.iter().rposition(|&group| group + 1 < k) else {
+            return ControlFlow::Continue(());
+        };
+        choice[i] += 1;
+        for group in &mut choice[i + 1..] {
+            *group = 0;
+        }
+    }
+}
+
+// Arguments of nested binary applications of the AC functor `name`, left to
+// right.
+fn flatten(name: &str, args: &[Expr]) -> Vec<Expr> {
+    let mut flat = Vec::new();
+    for arg in args {
+        match arg {
+            Expr::Fun(arg_name, arg_args) if arg_name == name && arg_args.len() == 2 => {
+                flat.extend(flatten(name, arg_args))
+            }
+            _ => flat.push(arg.clone()),
+        }
+    }
+    flat
+}
+
+fn nest(name: &str, mut args: Vec<Expr>) -> Expr {
+    let mut expr = args.pop().expect("groups are not empty");
+    while let Some(arg) = args.pop() {
+        expr = Expr::Fun(name.to_string(), vec![arg, expr]);
+    }
+    expr
+}
+
+/// Representative of the equivalence class of `expr`: AC functors flattened,
+/// arguments of AC and C functors sorted.
+pub fn canonical(expr: &Expr, theories: &Theories) -> Expr {
+    match expr {
+        Expr::Sym(_) | Expr::Var(_) | Expr::Num(_) => expr.clone(),
+        Expr::Fun(name, args) => {
+            // Theories only apply to binary functors.
+            let theory = theories.get(name).copied().filter(|_| args.len() == 2);
+            let args = match theory {
+                Some(Theory::AC) => flatten(name, args),
+                _ => args.clone(),
+            };
+            let mut args: Vec<Expr> = args.iter().map(|arg| canonical(arg, theories)).collect();
+            match theory {
+                Some(Theory::C) if args.len() == 2 => args.sort(),
+                Some(Theory::AC) => {
+                    args.sort();
+                    return nest(name, args);
+                }
+                _ => {}
+            }
+            Expr::Fun(name.clone(), args)
+        }
+    }
+}
+
+/// Whether `a` and `b` are equal modulo `theories`.
+pub fn equal_modulo(a: &Expr, b: &Expr, theories: &Theories) -> bool {
+    a == b || canonical(a, theories) == canonical(b, theories)
+}
+
+#[cfg(test)]
+fn theories(declared: &[(&str, Theory)]) -> Theories {
+    declared
+        .iter()
+        .map(|&(name, theory)| (name.to_string(), theory))
+        .collect()
+}
+
+#[cfg(test)]
+fn matches(pattern: &str, value: &str, theories: &Theories) -> Vec<String> {
+    pattern_match(&pattern.parse().unwrap(), &value.parse().unwrap(), theories)
+        .into_iter()
+        .map(|bindings| {
+            let mut bindings: Vec<String> = bindings
+                .iter()
+                .map(|(name, value)| format!("{} = {}", name, value))
+                .collect();
+            bindings.sort();
+            bindings.join(", ")
+        })
+        .collect()
+}
+
+#[test]
+fn match_modulo_commutativity() {
+    let theories = theories(&[("mul", Theory::C)]);
+    assert_eq!(matches("X * zero", "zero * a", &theories), ["X = a"]);
+    assert_eq!(
+        matches("X * Y", "a * b", &theories),
+        ["X = a, Y = b", "X = b, Y = a"]
+    );
+    assert_eq!(matches("X * X", "a * a", &theories), ["X = a"]);
+    assert!(matches("X * zero", "zero + a", &theories).is_empty());
+    // Syntactic matching gives at most one.
+    assert_eq!(
+        matches("X * Y", "a * b", &Theories::new()),
+        ["X = a, Y = b"]
+    );
+}
+
+#[test]
+fn match_modulo_associativity_and_commutativity() {
+    let theories = theories(&[("add", Theory::AC)]);
+    assert_eq!(
+        matches("X + zero", "a + (zero + b)", &theories),
+        ["X = a + b"]
+    );
+    assert_eq!(
+        matches("X + Y", "a + b + c", &theories),
+        [
+            "X = a + b, Y = c",
+            "X = a + c, Y = b",
+            "X = a, Y = b + c",
+            "X = b + c, Y = a",
+            "X = b, Y = a + c",
+            "X = c, Y = a + b",
+        ]
+    );
+    assert_eq!(
+        matches("s(X) + X", "a + s(b + a) + b", &theories),
+        ["X = b + a"]
+    );
+    assert!(matches("X + Y + Z", "a + b", &theories).is_empty());
+    assert!(matches("s(X) + X", "s(a) + b", &theories).is_empty());
+}
+
+#[test]
+fn rewrite_part_of_an_ac_application() {
+    let theories = theories(&[("add", Theory::AC)]);
+    let rewrite = |rule: &str, value: &str| {
+        let rule: Rule = rule.parse().unwrap();
+        rewrite(&rule, &value.parse().unwrap(), &theories)
+            .unwrap()
+            .map(|expr| expr.to_string())
+    };
+    let cancel = "X + neg(X) = zero";
+    assert_eq!(rewrite(cancel, "neg(a) + a").unwrap(), "zero");
+    assert_eq!(rewrite(cancel, "a + b + neg(a)").unwrap(), "zero + b");
+    assert_eq!(rewrite(cancel, "a + b + neg(c)"), None);
+    assert_eq!(rewrite("f(X + neg(X)) = zero", "f(a + b + neg(a))"), None);
+}
+
+#[test]
+fn canonical_forms() {
+    let theories = theories(&[("add", Theory::AC), ("mul", Theory::C)]);
+    let canonical = |expr: &str| canonical(&expr.parse().unwrap(), &theories).to_string();
+    assert_eq!(canonical("c + (b + a)"), "a + (b + c)");
+    assert_eq!(canonical("(b * a) * (d * c)"), "a * b * (c * d)");
+}
+
+#[test]
+fn rewrite_large_ac_applications_without_enumerating_every_match() {
+    // There are about 2^20 ways of splitting the sum between `X` and `Y`,
+    // rewriting only needs the first.
+    let theories = theories(&[("add", Theory::AC)]);
+    let rule: Rule = "f(X + Y) = g(Y + X)".parse().unwrap();
+    let sum: Vec<String> = (0..20).map(|i| format!("a{}", i)).collect();
+    let sum = sum.join(" + ");
+    let start = std::time::Instant::now();
+    let result = rewrite(&rule, &format!("f({})", sum).parse().unwrap(), &theories)
+        .unwrap()
+        .unwrap();
+    assert!(start.elapsed() < std::time::Duration::from_secs(1));
+    assert!(equal_modulo(
+        &result,
+        &format!("g({})", sum).parse().unwrap(),
+        &theories
+    ));
+}
@@ -0,0 +1,164 @@
+use crate::{is_var_name, substitute_bindings, Bindings, Expr, NoqError};
+
+/// Most general unifier of `a` and `b`, with variables on either side.
+///
+/// The returned bindings are fully resolved: no bound value mentions a bound
+/// variable, so substituting them into `a` and into `b` gives the same
+/// expression. A functor variable that would have to stand for something
+/// other than a symbol is a clash like any other, so `f(X, X(a))` and
+/// `f(g(b), Y)` do not unify.
+pub fn unify(a: &Expr, b: &Expr) -> Option<Bindings> {
+    let mut bindings = Bindings::new();
+    match unify_impl(a, b, &mut bindings) {
+        Ok(true) => Some(bindings),
+        Ok(false) | Err(NoqError::FunctorNotSymbol { .. }) => None,
+        Err(error) => unreachable!("substitution only fails on functors: {}", error),
+    }
+}
+
+fn unify_impl(a: &Expr, b: &Expr, bindings: &mut Bindings) -> Result<bool, NoqError> {
+    use Expr::*;
+    let a = substitute_bindings(bindings, a)?;
+    let b = substitute_bindings(bindings, b)?;
+    match (&a, &b) {
+        (Var(name1), Var(name2)) if name1 == name2 => Ok(true),
+        (Var(name), value) | (value, Var(name)) => {
+            if occurs(name, value) {
+                return Ok(false);
+            }
+            bind(name, value, bindings)?;
+            Ok(true)
+        }
+        (Sym(name1), Sym(name2)) => Ok(name1 == name2),
+        (Num(a), Num(b)) => Ok(a == b),
+        (Fun(name1, args1), Fun(name2, args2)) => {
+            if args1.len() != args2.len() {
+                return Ok(false);
+            }
+            // Functor variables are already substituted, so if either name
+            // is still a variable it is unbound.
+            if name1 != name2 {
+                let functor = |name: &String| match is_var_name(name) {
+                    true => Var(name.clone()),
+                    false => Sym(name.clone()),
+                };
+                if is_var_name(name1) {
+                    bind(name1, &functor(name2), bindings)?;
+                } else if is_var_name(name2) {
+                    bind(name2, &functor(name1), bindings)?;
+                } else {
+                    return Ok(false);
+                }
+            }
+            for (arg1, arg2) in args1.iter().zip(args2) {
+                if !unify_impl(arg1, arg2, bindings)? {
+                    return Ok(false);
+                }
+            }
+            Ok(true)
+        }
+        (Sym(_), _) | (Num(_), _) | (Fun(_, _), _) => Ok(false),
+    }
+}
+
+// Binds `name` to `value`, keeping the existing bindings resolved.
+fn bind(name: &str, value: &Expr, bindings: &mut Bindings) -> Result<(), NoqError> {
+    let binding = Bindings::from([(name.to_string(), value.clone())]);
+    for bound_value in bindings.values_mut() {
+        *bound_value = substitute_bindings(&binding, bound_value)?;
+    }
+    bindings.insert(name.to_string(), value.clone());
+    Ok(())
+}
+
+/// Renames every variable by appending `suffix`, so that two expressions can be
+/// unified without their variables getting mixed up.
+pub fn rename_vars(expr: &Expr, suffix: &str) -> Expr {
+    match expr {
+        Expr::Var(name) => Expr::Var(format!("{}{}", name, suffix)),
+        Expr::Sym(_) | Expr::Num(_) => expr.clone(),
+        Expr::Fun(name, args) => {
+            let name = match is_var_name(name) {
+                true => format!("{}{}", name, suffix),
+                false => name.clone(),
+            };
+            Expr::Fun(
+                name,
+                args.iter().map(|arg| rename_vars(arg, suffix)).collect(),
+            )
+        }
+    }
+}
+
+fn occurs(name: &str, expr: &Expr) -> bool {
+    match expr {
+        Expr::Var(var) => var == name,
+        Expr::Sym(_) | Expr::Num(_) => false,
+        Expr::Fun(functor, args) => functor == name || args.iter().any(|arg| occurs(name, arg)),
+    }
+}
+
+#[test]
+fn unify_binds_both_sides() {
+    let parse = |source: &str| source.parse::<Expr>().unwrap();
+    let a = parse("f(X, b, Z)");
+    let b = parse("f(a, Y, Y)");
+    let bindings = unify(&a, &b).unwrap();
+    assert_eq!(bindings["X"], parse("a"));
+    assert_eq!(bindings["Y"], parse("b"));
+    assert_eq!(bindings["Z"], parse("b"));
+    assert_eq!(
+        substitute_bindings(&bindings, &a).unwrap(),
+        substitute_bindings(&bindings, &b).unwrap()
+    );
+}
+
+#[test]
+fn unify_is_most_general() {
+    let parse = |source: &str| source.parse::<Expr>().unwrap();
+    let a = parse("f(X, g(Y))");
+    let b = parse("f(Z, Z)");
+    let bindings = unify(&a, &b).unwrap();
+    assert_eq!(bindings["X"], parse("g(Y)"));
+    assert_eq!(bindings["Z"], parse("g(Y)"));
+    assert!(!bindings.contains_key("Y"));
+}
+
+#[test]
+fn unify_fails_on_clashes_and_occurs_check() {
+    let unifies = |a: &str, b: &str| unify(&a.parse().unwrap(), &b.parse().unwrap()).is_some();
+    assert!(!unifies("f(a)", "f(b)"));
+    assert!(!unifies("f(X)", "g(X)"));
+    assert!(!unifies("f(X, Y)", "f(X)"));
+    assert!(!unifies("X", "f(X)"));
+    assert!(!unifies("f(X, Y)", "f(Y, g(X))"));
+    assert!(unifies("X", "X"));
+}
+
+#[test]
+fn unify_functor_variables() {
+    let parse = |source: &str| source.parse::<Expr>().unwrap();
+    let bindings = unify(&parse("F(X, b)"), &parse("g(a, Y)")).unwrap();
+    assert_eq!(bindings["F"], parse("g"));
+    assert_eq!(bindings["X"], parse("a"));
+    assert_eq!(bindings["Y"], parse("b"));
+
+    let a = parse("F(X, F(a))");
+    let b = parse("G(b, h(Y))");
+    let bindings = unify(&a, &b).unwrap();
+    assert_eq!(
+        substitute_bindings(&bindings, &a).unwrap(),
+        parse("h(b, h(a))")
+    );
+    assert_eq!(
+        substitute_bindings(&bindings, &b).unwrap(),
+        parse("h(b, h(a))")
+    );
+
+    assert!(unify(&parse("F(a)"), &parse("g(a, b)")).is_none());
+    assert!(unify(&parse("F(a, F)"), &parse("g(a, h)")).is_none());
+    // A functor variable bound to something other than a symbol, before or
+    // after it is used as a functor.
+    assert!(unify(&parse("f(X, X(a))"), &parse("f(g(b), Y)")).is_none());
+    assert!(unify(&parse("f(X(a), X)"), &parse("f(Y, g(b))")).is_none());
+}
@@ -0,0 +1,126 @@
+use noq::{
+    normal_form, normalize, parse_strategy, pattern_match,
+    theory::{Theories, Theory},
+    Assoc, Bindings, Expr, Lexer, NoqError, Operator, Operators, Rule, Stop, Strategy,
+    DEFAULT_MAX_STEPS,
+};
+
+fn expr(source: &str) -> Expr {
+    source.parse().unwrap()
+}
+
+#[test]
+fn parse_and_print_expressions() {
+    let parsed = expr("f(X, a + b * 2)");
+    assert_eq!(
+        parsed,
+        Expr::Fun(
+            "f".to_string(),
+            vec![
+                Expr::Var("X".to_string()),
+                Expr::Fun(
+                    "add".to_string(),
+                    vec![
+                        Expr::Sym("a".to_string()),
+                        Expr::Fun(
+                            "mul".to_string(),
+                            vec![Expr::Sym("b".to_string()), expr("2")]
+                        ),
+                    ]
+                ),
+            ]
+        )
+    );
+    assert_eq!(parsed.to_string(), "f(X, a + b * 2)");
+
+    let error = "f(a,".parse::<Expr>().unwrap_err();
+    assert!(matches!(error, NoqError::UnexpectedToken { .. }));
+    assert_eq!(
+        error.to_string(),
+        "1:5: expected symbol but got end of input"
+    );
+}
+
+#[test]
+fn parse_with_custom_operators() {
+    let mut operators = Operators::default();
+    operators.define(Operator {
+        symbol: "^".to_string(),
+        name: "pow".to_string(),
+        precedence: 3,
+        assoc: Assoc::Right,
+    });
+    let parsed = Expr::parse(&mut Lexer::new("a ^ b ^ c".chars()), &operators).unwrap();
+    assert_eq!(parsed, expr("pow(a, pow(b, c))"));
+    assert_eq!(operators.show(&parsed).to_string(), "a ^ b ^ c");
+}
+
+#[test]
+fn apply_rules_with_strategies() {
+    let rule: Rule = "f(X) = g(X)".parse().unwrap();
+    let apply = |source: &str, strategy: &Strategy| {
+        rule.apply(&expr(source), strategy)
+            .unwrap()
+            .map(|expr| expr.to_string())
+    };
+    assert_eq!(
+        apply("h(f(f(a)))", &Strategy::TopDown).unwrap(),
+        "h(g(f(a)))"
+    );
+    assert_eq!(
+        apply("h(f(f(a)))", &Strategy::BottomUp).unwrap(),
+        "h(g(g(a)))"
+    );
+    assert_eq!(
+        apply("h(f(f(a)))", &Strategy::At(vec![0, 0])).unwrap(),
+        "h(f(g(a)))"
+    );
+    assert_eq!(apply("h(a)", &Strategy::First), None);
+
+    let strategy = parse_strategy(&mut Lexer::new("at 1 0".chars())).unwrap();
+    assert_eq!(strategy, Strategy::At(vec![1, 0]));
+}
+
+#[test]
+fn match_patterns_modulo_theories() {
+    let pattern = expr("X + 0");
+    let value = expr("0 + a");
+    assert!(pattern_match(&pattern, &value, &Theories::new()).is_empty());
+
+    let mut theories = Theories::new();
+    theories.insert("add".to_string(), Theory::C);
+    let expected: Bindings = [("X".to_string(), expr("a"))].into_iter().collect();
+    assert_eq!(pattern_match(&pattern, &value, &theories), [expected]);
+}
+
+#[test]
+fn normalize_with_rules() {
+    let rules: Vec<(String, Rule)> = [("zero", "X + 0 = X"), ("succ", "X + s(Y) = s(X + Y)")]
+        .into_iter()
+        .map(|(name, source)| (name.to_string(), source.parse().unwrap()))
+        .collect();
+    let normalization = normalize(
+        &rules,
+        &Theories::new(),
+        &expr("a + s(s(0))"),
+        DEFAULT_MAX_STEPS,
+    )
+    .unwrap();
+    assert_eq!(normalization.stop, Stop::Normal);
+    let names: Vec<&str> = normalization
+        .steps
+        .iter()
+        .map(|step| step.rule_name.as_str())
+        .collect();
+    assert_eq!(names, ["succ", "succ", "zero"]);
+    assert_eq!(
+        normal_form(&rules, &expr("a + s(0)"), DEFAULT_MAX_STEPS).unwrap(),
+        Ok(expr("s(a)"))
+    );
+
+    let swap: Vec<(String, Rule)> = vec![("swap".to_string(), "X + Y = Y + X".parse().unwrap())];
+    assert_eq!(
+        normal_form(&swap, &expr("a + b"), DEFAULT_MAX_STEPS).unwrap(),
+        Err(expr("b + a"))
+    );
+}